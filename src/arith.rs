@@ -0,0 +1,364 @@
+use anyhow::anyhow;
+
+use crate::builtin::State;
+
+/// How deep variables may refer to other variables' expressions before evaluation gives up
+const MAX_DEPTH: usize = 1024;
+
+/// Operators, longest first so that e.g. `<<=` isn't read as `<` `<=`
+const OPERATORS: [&str; 39] = [
+  "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=",
+  "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "^", "|",
+  "?", ":", "(", ")", ",",
+];
+
+const ASSIGNMENTS: [&str; 11] = ["=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|="];
+
+/// Binary operators from the loosest to the tightest binding, below `&&`
+const LEVELS: [&[&str]; 8] = [
+  &["|"],
+  &["^"],
+  &["&"],
+  &["==", "!="],
+  &["<=", ">=", "<", ">"],
+  &["<<", ">>"],
+  &["+", "-"],
+  &["*", "/", "%"],
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+  Number(i64),
+  Name(String),
+  Operator(&'static str),
+}
+
+/// Parses a number literal: decimal, `0x` hex, `0` octal or `base#digits`
+fn number(literal: &str) -> Option<i64> {
+  let (base, digits) = if let Some((base, digits)) = literal.split_once('#') {
+    (base.parse().ok().filter(|base| (2..=64).contains(base))?, digits)
+  } else if let Some(hex) = literal.strip_prefix("0x").or(literal.strip_prefix("0X")) {
+    (16, hex)
+  } else if literal.len() > 1 && literal.starts_with('0') {
+    (8, &literal[1..])
+  } else {
+    (10, literal)
+  };
+  let mut value: i64 = 0;
+  for c in digits.chars() {
+    let digit = match c {
+      '0'..='9' => c as i64 - '0' as i64,
+      'a'..='z' => c as i64 - 'a' as i64 + 10,
+      'A'..='Z' if base <= 36 => c as i64 - 'A' as i64 + 10,
+      'A'..='Z' => c as i64 - 'A' as i64 + 36,
+      '@' => 62,
+      '_' => 63,
+      _ => return None,
+    };
+    if digit >= base {
+      return None;
+    }
+    value = value.wrapping_mul(base).wrapping_add(digit);
+  }
+  (!digits.is_empty()).then_some(value)
+}
+
+/// Splits `expr` into tokens along with the byte offset each starts at
+fn tokenize(expr: &str) -> anyhow::Result<Vec<(Token, usize)>> {
+  let mut tokens = vec![];
+  let mut i = 0;
+  while i < expr.len() {
+    let rest = &expr[i..];
+    let c = rest.chars().next().unwrap_or_default();
+    if c.is_whitespace() {
+      i += c.len_utf8();
+      continue;
+    }
+    let word_len = rest
+      .find(|c: char| !c.is_ascii_alphanumeric() && !['_', '#', '@'].contains(&c))
+      .unwrap_or(rest.len());
+    let token = if c.is_ascii_digit() {
+      let literal = &rest[..word_len];
+      let value = number(literal).ok_or_else(|| {
+        anyhow!("{expr}: value too great for base (error token is \"{literal}\")")
+      })?;
+      (Token::Number(value), word_len)
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+      (Token::Name(rest[..len].to_owned()), len)
+    } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+      (Token::Operator(op), op.len())
+    } else {
+      return Err(anyhow!(
+        "{expr}: syntax error: invalid arithmetic operator (error token is \"{rest}\")"
+      ));
+    };
+    tokens.push((token.0, i));
+    i += token.1;
+  }
+  Ok(tokens)
+}
+
+struct Evaluator<'a> {
+  expr: &'a str,
+  tokens: Vec<(Token, usize)>,
+  pos: usize,
+  state: &'a mut State,
+  depth: usize,
+  /// Set while evaluating the side of `&&`, `||` or `?:` that doesn't count, which mustn't assign
+  /// or fail on division by zero
+  skip: bool,
+}
+
+impl Evaluator<'_> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos).map(|(token, _)| token)
+  }
+
+  fn peek_operator(&self) -> Option<&'static str> {
+    match self.peek() {
+      Some(Token::Operator(op)) => Some(op),
+      _ => None,
+    }
+  }
+
+  fn eat(&mut self, op: &str) -> bool {
+    let found = self.peek_operator() == Some(op);
+    self.pos += found as usize;
+    found
+  }
+
+  /// The rest of the expression from the current token, as bash reports errors
+  fn rest(&self) -> &str {
+    self.tokens.get(self.pos).map_or("", |(_, i)| &self.expr[*i..])
+  }
+
+  fn error(&self, message: &str) -> anyhow::Error {
+    anyhow!("{}: {message} (error token is \"{}\")", self.expr, self.rest())
+  }
+
+  fn with_skip(
+    &mut self,
+    skip: bool,
+    f: impl FnOnce(&mut Self) -> anyhow::Result<i64>,
+  ) -> anyhow::Result<i64> {
+    let outer = self.skip;
+    self.skip |= skip;
+    let res = f(self);
+    self.skip = outer;
+    res
+  }
+
+  /// The value of a variable, itself evaluated as an expression, with unset or empty being 0
+  fn var(&mut self, name: &str) -> anyhow::Result<i64> {
+    let value = self.state.var(name).unwrap_or_default();
+    match value.trim() {
+      _ if self.skip => Ok(0),
+      "" => Ok(0),
+      value => match value.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => eval_nested(value, self.state, self.depth + 1),
+      },
+    }
+  }
+
+  fn assign(&mut self, name: &str, value: i64) -> anyhow::Result<i64> {
+    if !self.skip {
+      self.state.set_var(name, value.to_string())?;
+    }
+    Ok(value)
+  }
+
+  fn apply(&self, op: &str, l: i64, r: i64) -> anyhow::Result<i64> {
+    Ok(match op {
+      "/" | "%" if r == 0 => match self.skip {
+        true => 0,
+        false => return Err(self.error("division by 0")),
+      },
+      "/" => l.wrapping_div(r),
+      "%" => l.wrapping_rem(r),
+      "*" => l.wrapping_mul(r),
+      "+" => l.wrapping_add(r),
+      "-" => l.wrapping_sub(r),
+      "**" if r < 0 => return Err(self.error("exponent less than 0")),
+      "**" => l.wrapping_pow(r.min(u32::MAX as i64) as u32),
+      "<<" => l.wrapping_shl(r as u32),
+      ">>" => l.wrapping_shr(r as u32),
+      "<" => (l < r) as i64,
+      ">" => (l > r) as i64,
+      "<=" => (l <= r) as i64,
+      ">=" => (l >= r) as i64,
+      "==" => (l == r) as i64,
+      "!=" => (l != r) as i64,
+      "&" => l & r,
+      "^" => l ^ r,
+      "|" => l | r,
+      _ => unreachable!("not a binary operator: {op}"),
+    })
+  }
+
+  fn comma(&mut self) -> anyhow::Result<i64> {
+    let mut value = self.assignment()?;
+    while self.eat(",") {
+      value = self.assignment()?;
+    }
+    Ok(value)
+  }
+
+  fn assignment(&mut self) -> anyhow::Result<i64> {
+    let op = match self.tokens.get(self.pos + 1) {
+      Some((Token::Operator(op), _)) if ASSIGNMENTS.contains(op) => *op,
+      _ => return self.ternary(),
+    };
+    let Some(Token::Name(name)) = self.peek().cloned() else {
+      return self.ternary();
+    };
+    self.pos += 2;
+    let rhs = self.assignment()?;
+    let value = match op {
+      "=" => rhs,
+      _ => {
+        let current = self.var(&name)?;
+        self.apply(&op[..op.len() - 1], current, rhs)?
+      }
+    };
+    self.assign(&name, value)
+  }
+
+  fn ternary(&mut self) -> anyhow::Result<i64> {
+    let condition = self.or()?;
+    if !self.eat("?") {
+      return Ok(condition);
+    }
+    let then = self.with_skip(condition == 0, Self::assignment)?;
+    if !self.eat(":") {
+      return Err(self.error("syntax error: `:' expected for conditional expression"));
+    }
+    let otherwise = self.with_skip(condition != 0, Self::assignment)?;
+    Ok(if condition != 0 { then } else { otherwise })
+  }
+
+  fn or(&mut self) -> anyhow::Result<i64> {
+    let mut value = self.and()?;
+    while self.eat("||") {
+      let rhs = self.with_skip(value != 0, Self::and)?;
+      value = (value != 0 || rhs != 0) as i64;
+    }
+    Ok(value)
+  }
+
+  fn and(&mut self) -> anyhow::Result<i64> {
+    let mut value = self.binary(0)?;
+    while self.eat("&&") {
+      let rhs = self.with_skip(value == 0, |e| e.binary(0))?;
+      value = (value != 0 && rhs != 0) as i64;
+    }
+    Ok(value)
+  }
+
+  fn binary(&mut self, level: usize) -> anyhow::Result<i64> {
+    let Some(ops) = LEVELS.get(level) else {
+      return self.power();
+    };
+    let mut value = self.binary(level + 1)?;
+    while let Some(op) = self.peek_operator().filter(|op| ops.contains(op)) {
+      self.pos += 1;
+      let rhs = self.binary(level + 1)?;
+      value = self.apply(op, value, rhs)?;
+    }
+    Ok(value)
+  }
+
+  fn power(&mut self) -> anyhow::Result<i64> {
+    let base = self.unary()?;
+    if !self.eat("**") {
+      return Ok(base);
+    }
+    let exponent = self.power()?;
+    self.apply("**", base, exponent)
+  }
+
+  fn unary(&mut self) -> anyhow::Result<i64> {
+    let Some(op) = self.peek_operator() else {
+      return self.postfix();
+    };
+    match op {
+      "!" | "~" | "-" | "+" => {
+        self.pos += 1;
+        let value = self.unary()?;
+        Ok(match op {
+          "!" => (value == 0) as i64,
+          "~" => !value,
+          "-" => value.wrapping_neg(),
+          _ => value,
+        })
+      }
+      "++" | "--" => {
+        self.pos += 1;
+        let Some(Token::Name(name)) = self.peek().cloned() else {
+          return Err(self.error("syntax error: operand expected"));
+        };
+        self.pos += 1;
+        let value = self.var(&name)?.wrapping_add(if op == "++" { 1 } else { -1 });
+        self.assign(&name, value)
+      }
+      _ => self.postfix(),
+    }
+  }
+
+  fn postfix(&mut self) -> anyhow::Result<i64> {
+    match self.peek().cloned() {
+      Some(Token::Number(n)) => {
+        self.pos += 1;
+        Ok(n)
+      }
+      Some(Token::Name(name)) => {
+        self.pos += 1;
+        let value = self.var(&name)?;
+        if let Some(op) = self.peek_operator().filter(|op| ["++", "--"].contains(op)) {
+          self.pos += 1;
+          self.assign(&name, value.wrapping_add(if op == "++" { 1 } else { -1 }))?;
+        }
+        Ok(value)
+      }
+      Some(Token::Operator("(")) => {
+        self.pos += 1;
+        let value = self.comma()?;
+        if !self.eat(")") {
+          return Err(self.error("syntax error: `)' expected"));
+        }
+        Ok(value)
+      }
+      _ => Err(self.error("syntax error: operand expected")),
+    }
+  }
+}
+
+fn eval_nested(expr: &str, state: &mut State, depth: usize) -> anyhow::Result<i64> {
+  if depth > MAX_DEPTH {
+    return Err(anyhow!("{expr}: expression recursion level exceeded"));
+  }
+  let tokens = tokenize(expr)?;
+  let mut evaluator = Evaluator {
+    expr,
+    tokens,
+    pos: 0,
+    state,
+    depth,
+    skip: false,
+  };
+  if evaluator.tokens.is_empty() {
+    return Ok(0);
+  }
+  let value = evaluator.comma()?;
+  if evaluator.pos < evaluator.tokens.len() {
+    return Err(evaluator.error("syntax error in expression"));
+  }
+  Ok(value)
+}
+
+/// Evaluates an arithmetic expression as `$(( ))`, `let` and integer variables do, with C-like
+/// operators on 64-bit integers and variables standing for their values
+pub fn eval(expr: &str, state: &mut State) -> anyhow::Result<i64> {
+  eval_nested(expr, state, 0)
+}
@@ -1,14 +1,34 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::CString;
 use std::fmt::Display;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
-use std::{io::Write, str::FromStr};
+use std::fs::{File, OpenOptions};
+use std::iter::once;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{io, io::Read, io::Write, str::FromStr};
 
 use anyhow::{Context, anyhow};
 
-use crate::{CommandErr, CommandIn, CommandKind, CommandOut, ControlFlow};
+use crate::arith;
+use crate::color::{self, Color};
+use crate::edit::KillRing;
+use crate::expand::{is_name, tokenize_and_expand};
+use crate::history;
+use crate::jobs::{JobStatus, Jobs};
+use crate::options::{OPTIONS, ShellOptions};
+use crate::parse::{KEYWORDS, Node};
+use crate::signal::{self, Disposition};
+use crate::{
+  CommandErr, CommandIn, CommandOut, ControlFlow, PathIndex, Terminal, duplicate, is_executable,
+};
 
 #[repr(usize)]
 #[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 pub enum Builtin {
   Exit,
   Type,
@@ -16,26 +36,1007 @@ pub enum Builtin {
   Pwd,
   Cd,
   History,
+  Exec,
+  Set,
+  Export,
+  Umask,
+  Trap,
+  Hash,
+  Command,
+  Alias,
+  Unalias,
+  Builtin,
+  Pushd,
+  Popd,
+  Dirs,
+  Getopts,
+  Shift,
+  Declare,
+  Let,
+  Jobs,
+  Disown,
+  Printf,
+  Mapfile,
+  Complete,
+  Fc,
+  Shopt,
+  Read,
+  Wait,
+  Break,
+  Continue,
+}
+
+/// What a command name stands for, as `State::resolve` finds it
+#[derive(Debug)]
+pub enum Resolution {
+  /// An alias, with what it's replaced by
+  Alias(String),
+  /// A reserved word like `while`
+  Keyword,
+  Function(Rc<Node>),
+  Builtin(Builtin),
+  /// A program, where it was found
+  File(PathBuf),
+  NotFound,
+}
+
+/// How `complete` registered the arguments of a command to be completed
+#[derive(Debug, Clone, Default)]
+pub struct CompletionSpec {
+  /// The words of `-W`
+  pub words: Vec<String>,
+  /// `-d`, directories
+  pub dirs: bool,
+  /// `-f`, files of any kind
+  pub files: bool,
+}
+
+impl Display for CompletionSpec {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.dirs {
+      f.write_str("-d ")?;
+    }
+    if self.files {
+      f.write_str("-f ")?;
+    }
+    if !self.words.is_empty() {
+      // Quoted so that words with spaces read back as they were
+      let words = self.words.iter().map(|word| quote(word)).collect::<Vec<_>>();
+      write!(f, "-W {} ", single_quote(&words.join(" ")))?;
+    }
+    Ok(())
+  }
+}
+
+/// Fails a builtin that was called wrongly, printing the message, unless it's empty, and then
+/// the builtin's usage, with status 2
+#[derive(Debug)]
+pub struct Usage(pub String);
+
+impl Display for Usage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for Usage {}
+
+/// Exits a builtin with a nonzero status without printing an error
+#[derive(Debug)]
+pub struct Status(pub i32);
+
+impl Display for Status {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "exit status {}", self.0)
+  }
+}
+
+impl std::error::Error for Status {}
+
+#[derive(Debug, Clone, Default)]
+pub struct Variable {
+  pub value: String,
+  /// Whether the variable is passed on to the environment of child processes
+  pub exported: bool,
+  pub readonly: bool,
+  /// Whether assignments are evaluated as arithmetic, from `declare -i`
+  pub integer: bool,
+  /// The elements of an indexed array by index, in which case `value` is unused
+  pub array: Option<BTreeMap<usize, String>>,
 }
 
 pub struct State {
   pub control_flow: ControlFlow,
   pub history: Vec<String>,
+  /// When each history entry was run, in seconds since the epoch, if known
+  pub history_times: Vec<Option<i64>>,
   pub history_append_position: usize,
+  pub terminal: Terminal,
+  pub interactive: bool,
+  pub vars: BTreeMap<String, Variable>,
+  /// Exit status of the last pipeline, `$?`
+  pub status: i32,
+  /// Commands to run when a signal is caught, or on `signal::EXIT`
+  pub traps: BTreeMap<i32, String>,
+  pub path_index: PathIndex,
+  pub aliases: BTreeMap<String, String>,
+  pub functions: BTreeMap<String, Rc<Node>>,
+  /// `$1`, `$2`, ... of the running function
+  pub positional: Vec<String>,
+  /// Directories saved by `pushd`, most recent last. The current directory is the implicit top.
+  pub dir_stack: Vec<PathBuf>,
+  /// The `$OPTIND` `getopts` last left behind and the offset of the next option character in the
+  /// argument before it, for grouped options like `-ab`
+  pub getopts_position: (String, usize),
+  /// When `$SECONDS` started counting and the value it started from
+  pub seconds: (Instant, u64),
+  /// State of the generator behind `$RANDOM`
+  pub random: Cell<u32>,
+  /// The session-wide number of the first line of the input being run
+  pub input_line: usize,
+  /// `$LINENO`, the line of the command being run
+  pub line_number: usize,
+  pub jobs: Jobs,
+  /// The statuses of the stages of the last pipeline, `${PIPESTATUS[@]}`
+  pub pipe_status: Vec<i32>,
+  pub options: ShellOptions,
+  /// Completions registered with `complete`, by command name
+  pub completions: BTreeMap<String, CompletionSpec>,
+  /// Text cut from the command line, kept across lines like readline does
+  pub kill_ring: KillRing,
+  /// `$$`, the process ID of the shell, which subshells keep
+  pub pid: u32,
+  /// `$!`, the process ID of the last command run in the background
+  pub last_background: Option<libc::pid_t>,
+  /// The processes of `<(command)` and `>(command)` not yet waited for
+  pub substitutions: Vec<libc::pid_t>,
+  /// The shell's ends of the pipes to those processes, for the command being run to open
+  pub substitution_fds: Vec<File>,
+  /// How many function calls are being run, one inside the other
+  pub function_depth: usize,
+  /// How many loops are being run, one inside the other, for `break` and `continue` to leave
+  pub loop_depth: usize,
 }
 
 impl State {
-  pub fn new() -> Self {
-    Self {
+  pub fn new(terminal: Terminal) -> Self {
+    let mut state = Self {
       control_flow: ControlFlow::Repl,
       history: vec![],
+      history_times: vec![],
       history_append_position: 0,
+      terminal,
+      interactive: true,
+      vars: std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .map(|(name, value)| {
+          (
+            name,
+            Variable {
+              value,
+              exported: true,
+              ..Default::default()
+            },
+          )
+        })
+        .collect(),
+      status: 0,
+      traps: BTreeMap::new(),
+      path_index: PathIndex::default(),
+      aliases: BTreeMap::new(),
+      functions: BTreeMap::new(),
+      positional: vec![],
+      dir_stack: vec![],
+      getopts_position: (String::new(), 0),
+      seconds: (Instant::now(), 0),
+      random: Cell::new(
+        std::process::id()
+          ^ SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos(),
+      ),
+      input_line: 1,
+      line_number: 0,
+      jobs: Jobs::default(),
+      pipe_status: vec![],
+      options: ShellOptions::default(),
+      completions: BTreeMap::new(),
+      kill_ring: KillRing::default(),
+      pid: std::process::id(),
+      last_background: None,
+      substitutions: vec![],
+      substitution_fds: vec![],
+      function_depth: 0,
+      loop_depth: 0,
+    };
+    // Keep an inherited `$PWD` that still leads here, as it holds the logical path
+    if let Ok(cwd) = state.cwd() {
+      let var = state.vars.entry("PWD".to_owned()).or_default();
+      var.value = cwd.display().to_string();
+      var.exported = true;
+    }
+    // One more level of nesting than the shell that started this one, if any
+    let level = state.var("SHLVL").and_then(|level| level.trim().parse::<i64>().ok());
+    let var = state.vars.entry("SHLVL".to_owned()).or_default();
+    var.value = (level.unwrap_or(0).max(0) + 1).to_string();
+    var.exported = true;
+    state
+  }
+
+  /// What `name` runs as, in the order bash looks: an alias, a reserved word, a function, a
+  /// builtin, then a program
+  pub fn resolve(&mut self, name: &str) -> Resolution {
+    if let Some(value) = self.aliases.get(name) {
+      return Resolution::Alias(value.clone());
+    }
+    if KEYWORDS.contains(&name) {
+      return Resolution::Keyword;
+    }
+    self.resolve_command(name, true)
+  }
+
+  /// What `name` runs as once aliases and reserved words have been dealt with, as when it's run,
+  /// leaving out functions unless `functions`. A name with a `/` is the path to a program, rather
+  /// than one to look for on `$PATH`.
+  pub fn resolve_command(&mut self, name: &str, functions: bool) -> Resolution {
+    if functions && let Some(body) = self.functions.get(name) {
+      return Resolution::Function(body.clone());
+    }
+    if let Ok(builtin) = name.parse() {
+      return Resolution::Builtin(builtin);
+    }
+    let program = match name.contains('/') {
+      true => Some(PathBuf::from(name)).filter(|path| is_executable(path)),
+      false => self.path_index.lookup(name, &self.var("PATH").unwrap_or_default()),
+    };
+    program.map_or(Resolution::NotFound, Resolution::File)
+  }
+
+  /// Adds `line` to the history, stamped with the current time
+  pub fn push_history(&mut self, line: String) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    self.history.push(line);
+    self.history_times.push(Some(time as i64));
+  }
+
+  /// Joins `values` with the first character of `$IFS` as `"$*"` does: a space if it's unset, or
+  /// nothing if it's empty
+  pub fn join_with_ifs(&self, values: &[String]) -> String {
+    let separator = match self.var("IFS") {
+      Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+      None => " ".to_owned(),
+    };
+    values.join(&separator)
+  }
+
+  pub fn var(&self, name: &str) -> Option<String> {
+    match name {
+      "?" => Some(self.status.to_string()),
+      "$" => Some(self.pid.to_string()),
+      "!" => self.last_background.map(|pid| pid.to_string()),
+      "#" => Some(self.positional.len().to_string()),
+      "@" => Some(self.positional.join(" ")),
+      "*" => Some(self.join_with_ifs(&self.positional)),
+      "0" => std::env::args().next(),
+      "SECONDS" => Some((self.seconds.0.elapsed().as_secs() + self.seconds.1).to_string()),
+      "RANDOM" => {
+        // The same linear congruential generator as bash
+        let seed = self.random.get().wrapping_mul(1103515245).wrapping_add(12345);
+        self.random.set(seed);
+        Some(((seed >> 16) & 0x7fff).to_string())
+      }
+      "LINENO" => Some(self.line_number.to_string()),
+      _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+        self.positional.get(name.parse::<usize>().ok()?.checked_sub(1)?).cloned()
+      }
+      // An array stands for its first element
+      _ => self.vars.get(name).and_then(|var| match &var.array {
+        Some(array) => array.get(&0).cloned(),
+        None => Some(var.value.clone()),
+      }),
+    }
+  }
+
+  /// The elements of an array variable by index, where a plain variable is an array of its one
+  /// value
+  pub fn array(&self, name: &str) -> Option<BTreeMap<usize, String>> {
+    match name {
+      "PIPESTATUS" => Some(self.pipe_status.iter().map(i32::to_string).enumerate().collect()),
+      _ => match self.vars.get(name).and_then(|var| var.array.clone()) {
+        Some(array) => Some(array),
+        None => self.var(name).map(|value| BTreeMap::from([(0, value)])),
+      },
+    }
+  }
+
+  /// Checks that `name` may be assigned, evaluating `value` as arithmetic for integer variables
+  fn checked_value(&mut self, name: &str, value: String) -> anyhow::Result<String> {
+    let var = self.vars.get(name);
+    if var.is_some_and(|var| var.readonly) {
+      return Err(anyhow!("{name}: readonly variable"));
+    }
+    Ok(match var.is_some_and(|var| var.integer) {
+      true => arith::eval(&value, self)?.to_string(),
+      false => value,
+    })
+  }
+
+  /// Assigns a shell variable, keeping its attributes. Readonly variables refuse, and integer ones
+  /// are assigned the value of `value` as an arithmetic expression. `name[N]` assigns an element
+  /// of an array, and assigning an array by its name assigns its first element.
+  pub fn set_var(&mut self, name: &str, value: String) -> anyhow::Result<()> {
+    if let Some((name, subscript)) = name.strip_suffix(']').and_then(|name| name.split_once('[')) {
+      let index = subscript.parse().map_err(|_| anyhow!("{subscript}: bad array subscript"))?;
+      return self.set_element(name, index, value);
+    }
+    match name {
+      "SECONDS" => {
+        self.seconds = (Instant::now(), value.trim().parse().unwrap_or(0));
+        return Ok(());
+      }
+      "RANDOM" => {
+        self.random.set(value.trim().parse().unwrap_or(0));
+        return Ok(());
+      }
+      _ => {}
+    }
+    let value = self.checked_value(name, value)?;
+    let var = self.vars.entry(name.to_owned()).or_default();
+    match &mut var.array {
+      Some(array) => _ = array.insert(0, value),
+      None => var.value = value,
+    }
+    Ok(())
+  }
+
+  /// Assigns element `index` of the array `name`, turning a plain variable into an array whose
+  /// first element is its value
+  pub fn set_element(&mut self, name: &str, index: usize, value: String) -> anyhow::Result<()> {
+    let value = self.checked_value(name, value)?;
+    let existed = self.vars.contains_key(name);
+    let var = self.vars.entry(name.to_owned()).or_default();
+    let array = var.array.get_or_insert_with(|| match existed {
+      true => BTreeMap::from([(0, std::mem::take(&mut var.value))]),
+      false => BTreeMap::new(),
+    });
+    array.insert(index, value);
+    Ok(())
+  }
+
+  /// Assigns the array `name` the elements `values`, as `name=(values)` does
+  pub fn set_array(&mut self, name: &str, values: Vec<String>) -> anyhow::Result<()> {
+    let mut array = BTreeMap::new();
+    for (i, value) in values.into_iter().enumerate() {
+      array.insert(i, self.checked_value(name, value)?);
+    }
+    let var = self.vars.entry(name.to_owned()).or_default();
+    var.value.clear();
+    var.array = Some(array);
+    Ok(())
+  }
+
+  /// The logical working directory: `$PWD` if it still leads to the current directory, or the
+  /// physical path if not
+  pub fn cwd(&self) -> io::Result<PathBuf> {
+    let physical = std::env::current_dir()?;
+    let logical = self.var("PWD").map(PathBuf::from).filter(|pwd| pwd.is_absolute());
+    let same = |pwd: &PathBuf| {
+      let (Ok(a), Ok(b)) = (pwd.metadata(), physical.metadata()) else {
+        return false;
+      };
+      (a.dev(), a.ino()) == (b.dev(), b.ino())
+    };
+    Ok(logical.filter(same).unwrap_or(physical))
+  }
+
+  /// The environment passed to child processes, which has no place for arrays
+  pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+    self
+      .vars
+      .iter()
+      .filter(|(_, var)| var.exported && var.array.is_none())
+      .map(|(name, var)| (name.as_str(), var.value.as_str()))
+  }
+}
+
+/// Renders permission bits like `umask -S`, e.g. `u=rwx,g=rx,o=rx`
+fn symbolic_mode(perms: u32) -> String {
+  let class = |shift: u32| {
+    let bits = perms >> shift;
+    [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')]
+      .iter()
+      .filter(|(bit, _)| bits & bit != 0)
+      .map(|(_, c)| c)
+      .collect::<String>()
+  };
+  format!("u={},g={},o={}", class(6), class(3), class(0))
+}
+
+/// Parses an octal (`022`) or symbolic (`u=rwx,go-w`) umask spec relative to the `current` mask
+fn parse_umask(spec: &str, current: u32) -> anyhow::Result<u32> {
+  if spec.starts_with(|c: char| c.is_ascii_digit()) {
+    return u32::from_str_radix(spec, 8)
+      .ok()
+      .filter(|mask| *mask <= 0o777)
+      .ok_or(anyhow!("{spec}: octal number out of range"));
+  }
+
+  let mut perms = !current & 0o777;
+  for clause in spec.split(',') {
+    let op_position =
+      clause.find(['=', '+', '-']).ok_or(anyhow!("`{clause}': invalid symbolic mode operator"))?;
+    let (who, rest) = clause.split_at(op_position);
+    let mut classes = 0;
+    for c in who.chars() {
+      classes |= match c {
+        'u' => 0o700,
+        'g' => 0o070,
+        'o' => 0o007,
+        'a' => 0o777,
+        _ => return Err(anyhow!("`{c}': invalid symbolic mode operator")),
+      };
+    }
+    if classes == 0 {
+      classes = 0o777;
     }
+    let mut bits = 0;
+    for c in rest[1..].chars() {
+      bits |= match c {
+        'r' => 0o444,
+        'w' => 0o222,
+        'x' => 0o111,
+        _ => return Err(anyhow!("`{c}': invalid symbolic mode character")),
+      };
+    }
+    match &rest[..1] {
+      "=" => perms = (perms & !classes) | (bits & classes),
+      "+" => perms |= bits & classes,
+      _ => perms &= !(bits & classes),
+    }
+  }
+  Ok(!perms & 0o777)
+}
+
+fn single_quote(value: &str) -> String {
+  format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quotes `value` for `set` output so it can be read back by the shell
+fn quote(value: &str) -> String {
+  let safe = |c: char| c.is_ascii_alphanumeric() || "_./:,+-=@%^".contains(c);
+  if !value.is_empty() && value.chars().all(safe) { value.to_owned() } else { single_quote(value) }
+}
+
+/// Double-quotes `value` for `export -p` output, escaping the characters special inside `"..."`
+fn double_quote(value: &str) -> String {
+  let mut res = String::from('"');
+  for c in value.chars() {
+    if ['"', '\\', '$', '`'].contains(&c) {
+      res.push('\\');
+    }
+    res.push(c);
   }
+  res.push('"');
+  res
+}
+
+/// Renders the elements of an array the way they are assigned, e.g. `([0]="a" [1]="b")`
+fn array_literal(array: &BTreeMap<usize, String>) -> String {
+  let elements = array.iter().map(|(i, value)| format!("[{i}]={}", double_quote(value)));
+  format!("({})", elements.collect::<Vec<_>>().join(" "))
+}
+
+/// Renders a variable for `set` and `declare` output, which the shell can read back
+fn assignment(name: &str, var: &Variable) -> String {
+  match &var.array {
+    Some(array) => format!("{name}={}", array_literal(array)),
+    None => format!("{name}={}", quote(&var.value)),
+  }
+}
+
+/// Renders a variable like `declare -p`, e.g. `declare -ix n="5"`
+fn declaration(name: &str, var: &Variable) -> String {
+  let flags =
+    [(var.array.is_some(), 'a'), (var.integer, 'i'), (var.readonly, 'r'), (var.exported, 'x')];
+  let flags = flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag).collect::<String>();
+  let flags = if flags.is_empty() { "-".to_owned() } else { flags };
+  match &var.array {
+    Some(array) => format!("declare -{flags} {name}={}", array_literal(array)),
+    None => format!("declare -{flags} {name}={}", double_quote(&var.value)),
+  }
+}
+
+/// Appends what the backslash escape at the start of `s` (just past the `\`) stands for, as
+/// `printf` reads them, returning how many bytes of `s` it took. Octal escapes in `%b` arguments
+/// start with `\0`, which `zero_octal` asks for. `None` is `\c`, which ends the output there.
+fn escape(s: &[u8], zero_octal: bool, out: &mut Vec<u8>) -> Option<usize> {
+  // Reads up to `max` digits of `radix` from `s[start..]`, returning the value and the end
+  let digits = |start: usize, max: usize, radix: u32| {
+    let len = s[start.min(s.len())..]
+      .iter()
+      .take(max)
+      .take_while(|b| (**b as char).is_digit(radix))
+      .count();
+    let value = std::str::from_utf8(&s[start..start + len]).ok();
+    (value.and_then(|value| u32::from_str_radix(value, radix).ok()), start + len)
+  };
+  let Some(&c) = s.first() else {
+    out.push(b'\\');
+    return Some(0);
+  };
+  let simple = match c {
+    b'a' => Some(b'\x07'),
+    b'b' => Some(b'\x08'),
+    b'e' | b'E' => Some(b'\x1b'),
+    b'f' => Some(b'\x0c'),
+    b'n' => Some(b'\n'),
+    b'r' => Some(b'\r'),
+    b't' => Some(b'\t'),
+    b'v' => Some(b'\x0b'),
+    b'\\' | b'"' | b'\'' | b'?' => Some(c),
+    _ => None,
+  };
+  if let Some(byte) = simple {
+    out.push(byte);
+    return Some(1);
+  }
+  let (value, end) = match c {
+    b'c' => return None,
+    b'x' => digits(1, 2, 16),
+    b'u' => digits(1, 4, 16),
+    b'U' => digits(1, 8, 16),
+    b'0' if zero_octal => digits(1, 3, 8),
+    b'0'..=b'7' if !zero_octal => digits(0, 3, 8),
+    _ => (None, 0),
+  };
+  match (c, value) {
+    (b'u' | b'U', Some(value)) => {
+      let c = char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER);
+      out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    (_, Some(value)) => out.push(value as u8),
+    (b'0', None) => out.push(0),
+    _ => {
+      out.extend_from_slice(&[b'\\', c]);
+      return Some(1);
+    }
+  }
+  Some(end)
+}
+
+/// Where bytes that aren't UTF-8 sit in words, as the last 128 characters of Unicode's private
+/// use planes, one for each byte from `0x80` up
+const RAW_BYTES: u32 = 0x10FF00;
+
+/// `bytes` as text, keeping any that aren't UTF-8 as characters `raw_bytes` turns back into them,
+/// so `$'\xff'` still stands for the byte
+pub fn raw_text(bytes: &[u8]) -> String {
+  let mut text = String::new();
+  for chunk in bytes.utf8_chunks() {
+    text.push_str(chunk.valid());
+    text.extend(chunk.invalid().iter().filter_map(|&b| char::from_u32(RAW_BYTES + b as u32)));
+  }
+  text
+}
+
+/// The bytes `text` stands for, as programs and output get them, undoing `raw_text`
+pub fn raw_bytes(text: &str) -> Vec<u8> {
+  let mut bytes = vec![];
+  for c in text.chars() {
+    match (c as u32).checked_sub(RAW_BYTES) {
+      Some(b @ 0x80..=0xFF) => bytes.push(b as u8),
+      _ => bytes.extend(c.encode_utf8(&mut [0; 4]).bytes()),
+    }
+  }
+  bytes
+}
+
+/// Appends `s` with its backslash escapes replaced, returning `false` if a `\c` ended it early.
+/// `echo -e`, `printf %b` and `$'...'` all read escapes this way.
+pub fn interpret_escapes(s: &[u8], zero_octal: bool, out: &mut Vec<u8>) -> bool {
+  let mut i = 0;
+  while i < s.len() {
+    if s[i] == b'\\' {
+      let Some(len) = escape(&s[i + 1..], zero_octal, out) else {
+        return false;
+      };
+      i += len + 1;
+    } else {
+      out.push(s[i]);
+      i += 1;
+    }
+  }
+  true
+}
+
+/// Parses a numeric `printf` argument like C constants, or `'c` for the code of `c`, also
+/// returning whether all of it was a number
+fn printf_number(arg: &str) -> (i64, bool) {
+  if let Some(c) = arg.strip_prefix(['\'', '"']) {
+    return (c.chars().next().map_or(0, |c| c as i64), true);
+  }
+  let trimmed = arg.trim_start();
+  let (negative, digits) = match trimmed.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+  };
+  let (radix, digits) = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+    (16, hex)
+  } else if digits.len() > 1 && digits.starts_with('0') {
+    (8, &digits[1..])
+  } else {
+    (10, digits)
+  };
+  let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+  let value = i64::from_str_radix(&digits[..len], radix).unwrap_or(0);
+  let value = if negative { value.wrapping_neg() } else { value };
+  (value, len > 0 && len == digits.len() || arg.is_empty())
+}
+
+/// Formats `value` with a C conversion `spec` like `%-5d` through the C library, which
+/// knows every flag
+fn c_format<T: Copy>(spec: &str, value: T) -> Vec<u8> {
+  let Ok(spec) = CString::new(spec) else {
+    return vec![];
+  };
+  let mut buf = vec![0u8; 512];
+  loop {
+    let len = unsafe { libc::snprintf(buf.as_mut_ptr().cast(), buf.len(), spec.as_ptr(), value) };
+    let Ok(len) = usize::try_from(len) else {
+      return vec![];
+    };
+    if len < buf.len() {
+      buf.truncate(len);
+      return buf;
+    }
+    buf.resize(len + 1, 0);
+  }
+}
+
+/// Renders `printf` output into `out`, reusing `format` until the arguments are used up.
+/// Arguments that aren't valid numbers are reported on `stderr` and make the result an error.
+fn printf(
+  format: &str,
+  args: &[String],
+  out: &mut Vec<u8>,
+  stderr: &mut CommandErr,
+) -> anyhow::Result<()> {
+  let format = raw_bytes(format);
+  let format = format.as_slice();
+  let mut args = args.iter();
+  let mut invalid = false;
+  loop {
+    let remaining = args.len();
+    let mut i = 0;
+    while i < format.len() {
+      match format[i] {
+        b'\\' => {
+          let Some(len) = escape(&format[i + 1..], false, out) else {
+            return Ok(());
+          };
+          i += len + 1;
+          continue;
+        }
+        b'%' if format.get(i + 1) == Some(&b'%') => {
+          out.push(b'%');
+          i += 2;
+          continue;
+        }
+        b'%' => {}
+        byte => {
+          out.push(byte);
+          i += 1;
+          continue;
+        }
+      }
+
+      // `%`, then flags, width and precision, either of which may be `*` to take an argument
+      let mut spec = String::from("%");
+      i += 1;
+      while let Some(&flag) = format.get(i).filter(|b| b"-+ #0".contains(b)) {
+        spec.push(flag as char);
+        i += 1;
+      }
+      for part in [false, true] {
+        if part {
+          if format.get(i) != Some(&b'.') {
+            break;
+          }
+          spec.push('.');
+          i += 1;
+        }
+        if format.get(i) == Some(&b'*') {
+          spec.push_str(&printf_number(args.next().map_or("", String::as_str)).0.to_string());
+          i += 1;
+        }
+        while let Some(&digit) = format.get(i).filter(|b| b.is_ascii_digit()) {
+          spec.push(digit as char);
+          i += 1;
+        }
+      }
+      let Some(&conversion) = format.get(i) else {
+        return Err(anyhow!("`{spec}': missing format character"));
+      };
+      i += 1;
+
+      let arg = args.next().map_or("", String::as_str);
+      let mut number = || {
+        let (value, valid) = printf_number(arg);
+        if !valid {
+          _ = writeln!(stderr, "printf: {arg}: invalid number");
+          invalid = true;
+        }
+        value
+      };
+      match conversion {
+        b'd' | b'i' => out.extend(c_format(&format!("{spec}lld"), number())),
+        b'o' | b'u' | b'x' | b'X' => {
+          out.extend(c_format(&format!("{spec}ll{}", conversion as char), number()))
+        }
+        b'e' | b'E' | b'f' | b'F' | b'g' | b'G' | b'a' | b'A' => {
+          let value = arg.trim().parse::<f64>().unwrap_or_else(|_| number() as f64);
+          out.extend(c_format(&format!("{spec}{}", conversion as char), value));
+        }
+        b's' | b'b' | b'q' | b'c' => {
+          let mut text = vec![];
+          let stop = match conversion {
+            b'b' => !interpret_escapes(&raw_bytes(arg), true, &mut text),
+            b'q' => {
+              text.extend(quote(arg).bytes());
+              false
+            }
+            b'c' => {
+              text.extend(arg.chars().next().map(String::from).unwrap_or_default().bytes());
+              false
+            }
+            _ => {
+              text.extend(raw_bytes(arg));
+              false
+            }
+          };
+          // The rest of the spec works as for strings, byte by byte
+          let mut rest = spec[1..].split('.');
+          let flags_width = rest.next().unwrap_or_default();
+          if let Some(precision) = rest.next() {
+            text.truncate(precision.parse().unwrap_or(0));
+          }
+          let width = flags_width.trim_start_matches(['-', '+', ' ', '#', '0']);
+          let padding = width.parse::<usize>().unwrap_or(0).saturating_sub(text.len());
+          if flags_width.contains('-') {
+            out.extend(text);
+            out.extend(std::iter::repeat_n(b' ', padding));
+          } else {
+            out.extend(std::iter::repeat_n(b' ', padding));
+            out.extend(text);
+          }
+          if stop {
+            return Ok(());
+          }
+        }
+        _ => return Err(anyhow!("`{}': invalid format character", conversion as char)),
+      }
+    }
+    // The format is reused only while it takes arguments
+    if args.len() == 0 || args.len() == remaining {
+      break;
+    }
+  }
+  if invalid {
+    _ = stderr.flush();
+    return Err(Status(1).into());
+  }
+  Ok(())
+}
+
+/// Finds the history entry an `fc` argument refers to, returning its index: a number, counting
+/// back from the end if negative, or the start of a command
+fn history_entry(history: &[String], spec: &str) -> anyhow::Result<usize> {
+  let index = match spec.parse::<i64>() {
+    Ok(n) if n < 0 => history.len().checked_sub(n.unsigned_abs() as usize),
+    // Numbers past either end mean the first or last entry
+    Ok(n) => history.len().checked_sub(1).map(|last| (n.max(1) as usize - 1).min(last)),
+    Err(_) => history.iter().rposition(|line| line.starts_with(spec)),
+  };
+  index.ok_or(anyhow!("{spec}: history specification out of range"))
+}
+
+/// Points `target` at the same file as `fd`, leaving it untouched if they already match.
+fn redirect_fd(fd: RawFd, target: RawFd) -> io::Result<()> {
+  if fd != target && unsafe { libc::dup2(fd, target) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Has the user change `text` with `editor`, through a temporary file named after `name`, with the
+/// terminal as the editor expects it. Gives the text as it was saved, without trailing new lines.
+pub fn edit_text(
+  state: &mut State,
+  editor: &str,
+  name: &str,
+  text: &str,
+) -> anyhow::Result<String> {
+  let path = std::env::temp_dir().join(format!("{name}.{}", std::process::id()));
+  std::fs::write(&path, format!("{text}\n"))?;
+  state.terminal.restore();
+  crate::run_line(state, &format!("{editor} {}", single_quote(&path.to_string_lossy())));
+  state.terminal.set_raw();
+  let edited = std::fs::read_to_string(&path);
+  _ = std::fs::remove_file(&path);
+  if state.status != 0 {
+    return Err(Status(state.status).into());
+  }
+  Ok(edited?.trim_end_matches('\n').to_owned())
+}
+
+/// Reads up to `count` lines from `input`, or all of them, keeping their new lines if `newlines`.
+/// A last line without a new line still counts. Bytes are read one at a time, so that nothing past
+/// the last line taken is used up.
+fn read_lines(
+  input: &mut impl Read,
+  count: Option<usize>,
+  newlines: bool,
+) -> io::Result<Vec<String>> {
+  let mut lines = vec![];
+  let mut line = vec![];
+  let mut byte = [0];
+  while count.is_none_or(|count| lines.len() < count) {
+    if input.read(&mut byte)? == 0 {
+      if !line.is_empty() {
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+      }
+      break;
+    }
+    if byte[0] == b'\n' {
+      if newlines {
+        line.push(b'\n');
+      }
+      lines.push(String::from_utf8_lossy(&std::mem::take(&mut line)).into_owned());
+    } else {
+      line.push(byte[0]);
+    }
+  }
+  Ok(lines)
+}
+
+/// Splits a line `read` took into at most `count` fields on the characters of `ifs`, the last
+/// field taking the rest of the line. Bytes escaped with a backslash don't split.
+fn read_fields(line: &[(u8, bool)], ifs: &[u8], count: usize) -> Vec<String> {
+  let delimiter = |(byte, escaped): &(u8, bool)| !escaped && ifs.contains(byte);
+  let blank = |c: &(u8, bool)| delimiter(c) && c.0.is_ascii_whitespace();
+  let text = |bytes: &[(u8, bool)]| {
+    String::from_utf8_lossy(&bytes.iter().map(|(byte, _)| *byte).collect::<Vec<_>>()).into_owned()
+  };
+
+  let mut rest = &line[line.iter().take_while(|c| blank(c)).count()..];
+  rest = &rest[..rest.len() - rest.iter().rev().take_while(|c| blank(c)).count()];
+  let mut fields = vec![];
+  while !rest.is_empty() {
+    if fields.len() + 1 == count {
+      fields.push(text(rest));
+      break;
+    }
+    let len = rest.iter().position(delimiter).unwrap_or(rest.len());
+    fields.push(text(&rest[..len]));
+    rest = &rest[len..];
+    // Blanks around one other delimiter all make up a single split
+    let mut skip = rest.iter().take_while(|c| blank(c)).count();
+    if rest.get(skip).is_some_and(|c| delimiter(c) && !blank(c)) {
+      skip += 1;
+      skip += rest[skip..].iter().take_while(|c| blank(c)).count();
+    }
+    rest = &rest[skip..];
+  }
+  fields
+}
+
+/// Resolves `.` and `..` in `path` without following symlinks, so `..` leaves a symlinked
+/// directory the way it was entered
+fn normalize(path: &Path) -> PathBuf {
+  let mut res = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => _ = res.pop(),
+      component => res.push(component),
+    }
+  }
+  res
+}
+
+/// The directory `dir` names for `cd` and `pushd`, where only a leading `~` is `$HOME`, so other
+/// paths don't need it set
+fn tilde_dir(state: &State, dir: &str) -> anyhow::Result<String> {
+  match dir == "~" || dir.starts_with("~/") {
+    true => Ok(format!("{}{}", state.var("HOME").ok_or(anyhow!("HOME not set"))?, &dir[1..])),
+    false => Ok(dir.to_owned()),
+  }
+}
+
+/// Changes the working directory, keeping the logical path through symlinks in `$PWD` unless
+/// `physical`, and the previous one in `$OLDPWD`, both exported
+fn change_dir(state: &mut State, path: &Path, physical: bool) -> anyhow::Result<()> {
+  let old = state.cwd()?;
+  let logical = normalize(&old.join(path));
+  // The logical path can't be entered if a `..` undid a symlink to elsewhere, so fall back to
+  // letting the OS resolve it
+  let new = match !physical && std::env::set_current_dir(&logical).is_ok() {
+    true => logical,
+    false => {
+      std::env::set_current_dir(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => anyhow!("{}: No such file or directory", path.display()),
+        io::ErrorKind::NotADirectory => anyhow!("{}: Not a directory", path.display()),
+        io::ErrorKind::PermissionDenied => anyhow!("{}: Permission denied", path.display()),
+        _ => anyhow!("{}: {e}", path.display()),
+      })?;
+      std::env::current_dir()?
+    }
+  };
+  for (name, value) in [("OLDPWD", old), ("PWD", new)] {
+    state.set_var(name, value.display().to_string())?;
+    state.vars.entry(name.to_owned()).or_default().exported = true;
+  }
+  Ok(())
+}
+
+/// The directory stack as `dirs` shows it, current directory first, with `$HOME` shown as `~`
+fn dirs(state: &State) -> anyhow::Result<Vec<String>> {
+  let home = state.var("HOME").filter(|home| !home.is_empty());
+  let current = state.cwd()?;
+  let stack = once(&current).chain(state.dir_stack.iter().rev());
+  let dirs = stack.map(|dir| {
+    let dir = dir.display().to_string();
+    match home.as_deref().and_then(|home| dir.strip_prefix(home)) {
+      Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+      _ => dir,
+    }
+  });
+  Ok(dirs.collect())
 }
 
 impl Builtin {
-  pub const TO_STRING: [&'static str; 6] = ["exit", "type", "echo", "pwd", "cd", "history"];
+  pub const TO_STRING: [&'static str; 34] = [
+    "exit", "type", "echo", "pwd", "cd", "history", "exec", "set", "export", "umask", "trap",
+    "hash", "command", "alias", "unalias", "builtin", "pushd", "popd", "dirs", "getopts", "shift",
+    "declare", "let", "jobs", "disown", "printf", "mapfile", "complete", "fc", "shopt", "read",
+    "wait", "break", "continue",
+  ];
+
+  /// How each builtin is called, in the same order as `TO_STRING`
+  pub const USAGE: [&'static str; 34] = [
+    "exit [n]",
+    "type [-t] name [name ...]",
+    "echo [-neE] [arg ...]",
+    "pwd [-P]",
+    "cd [-L|-P] [dir]",
+    "history [n] [-r|-w|-a [filename]]",
+    "exec [command [argument ...]]",
+    "set [-Hnu] [-o option-name] [+Hnu] [+o option-name] [--] [arg ...]",
+    "export [-n] [-p] [name[=value] ...]",
+    "umask [-S] [mode]",
+    "trap [-lp] [[action] signal_spec ...]",
+    "hash [-r] [name ...]",
+    "command [-pV] command [arg ...]",
+    "alias [name[=value] ...]",
+    "unalias [-a] name [name ...]",
+    "builtin [shell-builtin [arg ...]]",
+    "pushd [dir]",
+    "popd",
+    "dirs [-c] [-v]",
+    "getopts optstring name [arg ...]",
+    "shift [n]",
+    "declare [-aiprx] [name[=value] ...]",
+    "let arg [arg ...]",
+    "jobs [-lp]",
+    "disown [-ah] [jobspec ... | pid ...]",
+    "printf [-v var] format [arguments]",
+    "mapfile [-t] [-n count] [array]",
+    "complete [-dfpr] [-W wordlist] [name ...]",
+    "fc [-e ename] [-lnr] [first] [last] or fc -s [pat=rep] [command]",
+    "shopt [-pqsu] [-o] [optname ...]",
+    "read [-rs] [-d delim] [-p prompt] [-t timeout] [name ...]",
+    "wait [-n] [id ...]",
+    "break [n]",
+    "continue [n]",
+  ];
 
   pub fn run(
     &self,
@@ -43,11 +1044,25 @@ impl Builtin {
     stdout: CommandOut,
     mut stderr: CommandErr,
     stdin: Option<CommandIn>,
-    paths: &Vec<PathBuf>,
     args: Vec<String>,
   ) -> anyhow::Result<()> {
-    if let Err(e) = self._run(state, stdout, &mut stderr, stdin, paths, args) {
-      writeln!(stderr, "{self}: {e}")?;
+    if let Err(e) = self._run(state, stdout, &mut stderr, stdin, args) {
+      // A reader that went away doesn't want the rest of the output, which is no error of ours,
+      // so stop quietly with the status a process killed by `SIGPIPE` would have
+      if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) {
+        return Err(Status(128 + libc::SIGPIPE).into());
+      }
+      if let Some(Usage(message)) = e.downcast_ref() {
+        if !message.is_empty() {
+          writeln!(stderr, "{self}: {message}")?;
+        }
+        writeln!(stderr, "{self}: usage: {}", Builtin::USAGE[*self as usize])?;
+        return Err(Status(2).into());
+      }
+      if !e.is::<Status>() {
+        let colored = color::enabled(state, stderr.is_terminal());
+        writeln!(stderr, "{self}: {}", color::paint(&e, Color::Red, colored))?;
+      }
       return Err(e);
     };
     Ok(())
@@ -58,42 +1073,189 @@ impl Builtin {
     state: &mut State,
     mut stdout: CommandOut,
     stderr: &mut CommandErr,
-    _stdin: Option<CommandIn>,
-    paths: &Vec<PathBuf>,
+    stdin: Option<CommandIn>,
     args: Vec<String>,
   ) -> anyhow::Result<()> {
     match self {
-      Builtin::Exit => state.control_flow = ControlFlow::Exit,
+      Builtin::Exit => {
+        let status = match args.as_slice() {
+          [] => state.status,
+          [status] => match status.parse::<i64>() {
+            Ok(status) => (status & 0xFF) as i32,
+            Err(_) => {
+              writeln!(stderr, "exit: {status}: numeric argument required")?;
+              2
+            }
+          },
+          _ => return Err(anyhow!("too many arguments")),
+        };
+        state.control_flow = ControlFlow::Exit;
+        return Err(Status(status).into());
+      }
       Builtin::Type => {
-        for arg in args {
-          match CommandKind::parse(&arg, paths) {
-            CommandKind::Builtin(name) => writeln!(stdout, "{name} is a shell builtin")?,
-            CommandKind::Program(path) => writeln!(stdout, "{}", path.display())?,
-            CommandKind::NotFound(name) => writeln!(stderr, "{name}: not found")?,
+        // `-t` prints just the kind of each command
+        let terse = args.first().is_some_and(|arg| arg == "-t");
+        let colored = color::enabled(state, stdout.is_terminal());
+        for arg in args.into_iter().skip(terse as usize) {
+          // Looking the command up hashes it, which `type` only reports on rather than does
+          let path = state.var("PATH").unwrap_or_default();
+          let hashed = state.path_index.hashed(&arg, &path).is_some();
+          let resolution = state.resolve(&arg);
+          if !hashed {
+            state.path_index.commands.remove(&arg);
+          }
+          if terse {
+            match resolution {
+              Resolution::Alias(_) => writeln!(stdout, "alias")?,
+              Resolution::Keyword => writeln!(stdout, "keyword")?,
+              Resolution::Function(_) => writeln!(stdout, "function")?,
+              Resolution::Builtin(_) => writeln!(stdout, "builtin")?,
+              Resolution::File(_) => writeln!(stdout, "file")?,
+              Resolution::NotFound => {}
+            }
+            continue;
+          }
+          match resolution {
+            Resolution::Alias(value) => writeln!(stdout, "{arg} is aliased to `{value}'")?,
+            Resolution::Keyword => writeln!(stdout, "{arg} is a shell keyword")?,
+            Resolution::Function(_) => {
+              writeln!(stdout, "{arg} is a {}", color::paint("function", Color::Magenta, colored))?
+            }
+            Resolution::Builtin(name) => {
+              let kind = color::paint("shell builtin", Color::Cyan, colored);
+              writeln!(stdout, "{name} is a {kind}")?
+            }
+            Resolution::File(path) if hashed => {
+              writeln!(stdout, "{arg} is hashed ({})", path.display())?
+            }
+            Resolution::File(path) => writeln!(stdout, "{}", path.display())?,
+            Resolution::NotFound => writeln!(stderr, "{arg}: not found")?,
           }
         }
       }
-      Builtin::Echo => writeln!(stdout, "{}", args.join(" "))?,
+      Builtin::Echo => {
+        // Leading words made only of option letters are options, unless POSIX says echo has none
+        let is_option = |arg: &&String| {
+          !state.options.posix
+            && arg.len() > 1
+            && arg.strip_prefix('-').is_some_and(|flags| flags.chars().all(|c| "neE".contains(c)))
+        };
+        let options = args.iter().take_while(is_option).count();
+        let (mut newline, mut escapes) = (true, false);
+        for flag in args[..options].iter().flat_map(|arg| arg[1..].chars()) {
+          match flag {
+            'n' => newline = false,
+            'e' => escapes = true,
+            _ => escapes = false,
+          }
+        }
+        // One space between arguments, whatever separated them; spaces inside quotes are part of
+        // the argument
+        let text = raw_bytes(&args[options..].join(" "));
+        let mut out = vec![];
+        if escapes && !interpret_escapes(&text, true, &mut out) {
+          newline = false;
+        } else if !escapes {
+          out = text;
+        }
+        if newline {
+          out.push(b'\n');
+        }
+        stdout.write_all(&out)?;
+      }
+      Builtin::Printf => {
+        // `-v name` puts the output in a variable rather than printing it
+        let (var, args) = match args.as_slice() {
+          [flag, name, args @ ..] if flag == "-v" => (Some(name), args),
+          [flag] if flag == "-v" => {
+            return Err(Usage("-v: option requires an argument".to_owned()).into());
+          }
+          args => (None, args),
+        };
+        if let Some(name) = var.filter(|name| !is_name(name.split('[').next().unwrap_or_default()))
+        {
+          return Err(anyhow!("`{name}': not a valid identifier"));
+        }
+        let Some((format, args)) = args.split_first() else {
+          return Err(Usage(String::new()).into());
+        };
+        // Write what was formatted even when an argument was bad
+        let mut out = vec![];
+        let res = printf(format, args, &mut out, stderr);
+        match var {
+          Some(name) => state.set_var(name, raw_text(&out))?,
+          None => stdout.write_all(&out)?,
+        }
+        res?;
+      }
       Builtin::Pwd => {
-        let path = std::env::current_dir()?;
+        let path = match args.first().map(String::as_str) {
+          Some("-P") => std::env::current_dir()?,
+          _ => state.cwd()?,
+        };
         writeln!(stdout, "{}", path.display())?;
       }
       Builtin::Cd => {
-        let home = std::env::var("HOME")?;
-        let path: PathBuf = args.first().unwrap_or(&"~".to_owned()).replace("~", &home).into();
-        std::env::set_current_dir(&path)
-          .context(format!("{}: No such file or directory", path.display()))?;
+        // `-P` resolves symlinks, while `-L`, the default, keeps them in `$PWD`
+        let mut physical = false;
+        let mut args = args.as_slice();
+        while let Some(arg) = args.first().filter(|arg| arg.starts_with('-') && arg.len() > 1) {
+          args = &args[1..];
+          if arg == "--" {
+            break;
+          }
+          for flag in arg[1..].chars() {
+            match flag {
+              'L' => physical = false,
+              'P' => physical = true,
+              _ => return Err(Usage(format!("-{flag}: invalid option")).into()),
+            }
+          }
+        }
+        if args.len() > 1 {
+          return Err(Usage("too many arguments".to_owned()).into());
+        }
+        let path = match args.first().map(String::as_str) {
+          None => state.var("HOME").ok_or(anyhow!("HOME not set"))?,
+          Some("-") => state.var("OLDPWD").ok_or(anyhow!("OLDPWD not set"))?,
+          Some(dir) => tilde_dir(state, dir)?,
+        };
+        change_dir(state, Path::new(&path), physical)?;
+        // `cd -` shows where it went
+        if args.first().is_some_and(|arg| arg == "-") {
+          writeln!(stdout, "{}", state.cwd()?.display())?;
+        }
       }
       Builtin::History => {
         let [mut r, mut w, mut a] = [None, None, None];
         let mut n = None;
 
-        let mut args = args.into_iter();
+        let mut args = args.into_iter().peekable();
         while let Some(arg) = args.next() {
           match arg.as_str() {
-            "-r" => r = Some(args.next().ok_or(anyhow!("expected <history_file>"))?),
-            "-w" => w = Some(args.next().ok_or(anyhow!("expected <history_file>"))?),
-            "-a" => a = Some(args.next().ok_or(anyhow!("expected <history_file>"))?),
+            "-r" | "-w" | "-a" => {
+              // Without a file of its own, the history file is `$HISTFILE`
+              let file = args
+                .next_if(|file| !file.starts_with('-'))
+                .or_else(|| state.var("HISTFILE"))
+                .filter(|file| !file.is_empty())
+                .ok_or_else(|| Usage(format!("{arg}: history file not set")))?;
+              let file = match file.strip_prefix('~') {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                  format!("{}{rest}", state.var("HOME").ok_or(anyhow!("HOME not set"))?)
+                }
+                _ => file,
+              };
+              let file = Some(file);
+              match arg.as_str() {
+                "-r" => r = file,
+                "-w" => w = file,
+                _ => a = file,
+              }
+            }
+            val if val.starts_with('-') => {
+              return Err(Usage(format!("{val}: invalid option")).into());
+            }
             val => {
               let val = val.parse().context(format!("could not parse number `{val}`"))?;
               args.next().map_or(Ok(()), |e| Err(anyhow!("unexpected argument `{e}`")))?;
@@ -108,43 +1270,984 @@ impl Builtin {
 
         if let Some(history_file_path) = r {
           n.map_or(Ok(()), |n| Err(anyhow!("unexpected argument {n}")))?;
-          state.history.append(
-            &mut std::fs::read_to_string(&history_file_path)
-              .map(|x| x.lines().map(str::to_owned).collect::<Vec<_>>())
-              .context(format!("unable to read file `{history_file_path}`"))?,
-          );
+          let (mut lines, mut times) = std::fs::read_to_string(&history_file_path)
+            .map(|content| history::load(&content))
+            .context(format!("unable to read file `{history_file_path}`"))?;
+          state.history.append(&mut lines);
+          state.history_times.append(&mut times);
           return Ok(());
         }
 
         if let Some(history_file_path) = a {
           n.map_or(Ok(()), |n| Err(anyhow!("unexpected argument {n}")))?;
-          let shown = state.history[state.history_append_position..].join("\n");
-          state.history_append_position = state.history[state.history_append_position..].len();
+          let shown = history::save(
+            &state.history[state.history_append_position..],
+            &state.history_times[state.history_append_position..],
+            state.var("HISTTIMEFORMAT").is_some(),
+          );
           OpenOptions::new()
             .append(true)
             .create(true)
             .open(&history_file_path)
             .context(format!("unable to open file `{history_file_path}`"))?
-            .write_all((shown + "\n").as_bytes())
+            .write_all(shown.as_bytes())
             .context(format!("unable to write to file `{history_file_path}`"))?;
+          // Only what comes after is new to the file
+          state.history_append_position = state.history.len();
           return Ok(());
         }
 
         let n = n.unwrap_or(state.history.len());
-        let shown = state.history.iter().enumerate().rev().take(n).rev();
+        let first = state.history.len().saturating_sub(n);
+        let time_format = state.var("HISTTIMEFORMAT");
 
         if let Some(history_file_path) = w {
-          let shown = shown.map(|(_, s)| s.clone()).collect::<Vec<_>>().join("\n");
+          let shown = history::save(
+            &state.history[first..],
+            &state.history_times[first..],
+            time_format.is_some(),
+          );
           std::fs::File::create(&history_file_path)
             .context(format!("unable to open file `{history_file_path}`"))?
-            .write_all((shown + "\n").as_bytes())
+            .write_all(shown.as_bytes())
             .context(format!("unable to write to file `{history_file_path}`"))?;
           return Ok(());
         }
 
-        let out = shown.map(|(i, s)| format!("{:>5}  {s}", i + 1)).collect::<Vec<_>>().join("\n");
-        writeln!(stdout, "{out}")?;
+        for (i, line) in state.history.iter().enumerate().skip(first) {
+          // The format is written right before the command, so usually ends in a space
+          let time = match (&time_format, state.history_times[i]) {
+            (Some(format), Some(time)) => history::format_time(format, time),
+            _ => String::new(),
+          };
+          writeln!(stdout, "{:>5}  {time}{line}", i + 1)?;
+        }
+      }
+      Builtin::Exec => {
+        // Without a command the redirections become permanent for the shell itself
+        redirect_fd(stdout.as_raw_fd(), libc::STDOUT_FILENO)?;
+        redirect_fd(stderr.as_raw_fd(), libc::STDERR_FILENO)?;
+        if let Some(stdin) = &stdin {
+          redirect_fd(stdin.as_raw_fd(), libc::STDIN_FILENO)?;
+        }
+        let Some(program) = args.first() else {
+          return Ok(());
+        };
+        // `exec` runs a program, even one named like a builtin, found on the shell's `$PATH`
+        let path = match program.contains('/') {
+          true => Some(PathBuf::from(program)),
+          false => state.path_index.lookup(program, &state.var("PATH").unwrap_or_default()),
+        };
+        let Some(path) = path else {
+          writeln!(stderr, "exec: {program}: not found")?;
+          if !state.interactive {
+            state.control_flow = ControlFlow::Exit;
+          }
+          return Err(Status(127).into());
+        };
+
+        let path = CString::new(path.into_os_string().into_encoded_bytes())?;
+        let args =
+          args.iter().map(|arg| CString::new(raw_bytes(arg))).collect::<Result<Vec<_>, _>>()?;
+        let argv =
+          args.iter().map(|arg| arg.as_ptr()).chain(once(std::ptr::null())).collect::<Vec<_>>();
+        let env =
+          state.exported().map(|(name, value)| CString::new(raw_bytes(&format!("{name}={value}"))));
+        let env = env.collect::<Result<Vec<_>, _>>()?;
+        let envp =
+          env.iter().map(|var| var.as_ptr()).chain(once(std::ptr::null())).collect::<Vec<_>>();
+        state.terminal.restore();
+        unsafe { libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+
+        // execve only returns on failure: 127 if there was no such file, as when it wasn't found,
+        // and 126 if it couldn't be run
+        let err = io::Error::last_os_error();
+        state.terminal.set_raw();
+        if !state.interactive {
+          state.control_flow = ControlFlow::Exit;
+        }
+        let status = match err.kind() {
+          io::ErrorKind::NotFound => {
+            writeln!(stderr, "exec: {program}: not found")?;
+            127
+          }
+          io::ErrorKind::PermissionDenied => {
+            writeln!(stderr, "exec: {program}: Permission denied")?;
+            126
+          }
+          _ => {
+            writeln!(stderr, "exec: {program}: {err}")?;
+            126
+          }
+        };
+        return Err(Status(status).into());
+      }
+      Builtin::Set => {
+        if args.is_empty() {
+          for (name, var) in &state.vars {
+            writeln!(stdout, "{}", assignment(name, var))?;
+          }
+        }
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+          let enable = arg.starts_with('-');
+          let name = match arg.as_str() {
+            // The rest are the positional parameters, even none of them after `--`
+            "--" => {
+              state.positional = args.cloned().collect();
+              break;
+            }
+            _ if !arg.starts_with(['-', '+']) => {
+              state.positional = once(arg).chain(args).cloned().collect();
+              break;
+            }
+            "-o" | "+o" => args.next().map(String::as_str),
+            "-H" | "+H" => Some("histexpand"),
+            "-n" | "+n" => Some("noexec"),
+            "-u" | "+u" => Some("nounset"),
+            _ => return Err(Usage(format!("{arg}: invalid option")).into()),
+          };
+          match name {
+            Some(name) => {
+              match state.options.get_mut(name).filter(|_| OPTIONS.contains(&(name, true))) {
+                Some(on) => *on = enable,
+                None => return Err(Usage(format!("{name}: invalid option name")).into()),
+              }
+            }
+            None => {
+              for (name, _) in OPTIONS.iter().filter(|(_, set)| *set) {
+                let on = state.options.get(name) == Some(true);
+                match enable {
+                  true => writeln!(stdout, "{name:<15}\t{}", if on { "on" } else { "off" })?,
+                  false => writeln!(stdout, "set {}o {name}", if on { '-' } else { '+' })?,
+                }
+              }
+            }
+          }
+        }
+      }
+      Builtin::Export => {
+        let mut args = args.as_slice();
+        let unexport = args.first().is_some_and(|arg| arg == "-n");
+        if args.first().is_some_and(|arg| arg == "-p" || arg == "-n") {
+          args = &args[1..];
+        }
+        if args.is_empty() {
+          for (name, var) in state.vars.iter().filter(|(_, var)| var.exported) {
+            writeln!(stdout, "declare -x {name}={}", double_quote(&var.value))?;
+          }
+        }
+        for arg in args {
+          let (name, value) =
+            arg.split_once('=').map_or((arg.as_str(), None), |(n, v)| (n, Some(v)));
+          if !is_name(name) {
+            return Err(anyhow!("`{arg}': not a valid identifier"));
+          }
+          if let Some(value) = value {
+            state.set_var(name, value.to_owned())?;
+          }
+          if let Some(var) = state.vars.get_mut(name) {
+            var.exported = !unexport;
+          }
+        }
+      }
+      Builtin::Umask => {
+        let symbolic = args.first().is_some_and(|arg| arg == "-S");
+        let args = if symbolic { &args[1..] } else { &args[..] };
+        // umask can only be read by setting it, so put it straight back
+        let current = unsafe {
+          let mask = libc::umask(0);
+          libc::umask(mask);
+          mask
+        } as u32;
+        match args.first() {
+          None if symbolic => writeln!(stdout, "{}", symbolic_mode(!current & 0o777))?,
+          None => writeln!(stdout, "{current:04o}")?,
+          Some(spec) => {
+            let mask = parse_umask(spec, current)?;
+            unsafe { libc::umask(mask as libc::mode_t) };
+          }
+        }
+      }
+      Builtin::Trap => match args.first().map(String::as_str) {
+        Some("-l") => {
+          let signals =
+            signal::SIGNALS.iter().map(|(number, name)| format!("{number:2}) SIG{name}"));
+          for row in signals.collect::<Vec<_>>().chunks(5) {
+            writeln!(stdout, "{}", row.join("\t"))?;
+          }
+        }
+        None | Some("-p") => {
+          for (number, command) in &state.traps {
+            writeln!(stdout, "trap -- {} {}", single_quote(command), signal::name(*number))?;
+          }
+        }
+        Some(command) => {
+          // A lone signal resets it, like `trap - SIGNAL`
+          let (command, signals) =
+            if args.len() == 1 { ("-", &args[..]) } else { (command, &args[1..]) };
+          for name in signals {
+            let number =
+              signal::parse(name).ok_or(anyhow!("{name}: invalid signal specification"))?;
+            let disposition = match command {
+              "-" => {
+                state.traps.remove(&number);
+                Disposition::Default
+              }
+              "" => Disposition::Ignore,
+              _ => Disposition::Trap,
+            };
+            if command != "-" {
+              state.traps.insert(number, command.to_owned());
+            }
+            signal::set_disposition(number, disposition);
+          }
+        }
+      },
+      Builtin::Hash => {
+        if args.first().is_some_and(|arg| arg == "-r") {
+          state.path_index.commands.clear();
+          return Ok(());
+        }
+        if args.is_empty() {
+          if state.path_index.commands.is_empty() {
+            writeln!(stdout, "hash: hash table empty")?;
+            return Ok(());
+          }
+          writeln!(stdout, "hits\tcommand")?;
+          for (location, hits) in state.path_index.commands.values() {
+            writeln!(stdout, "{hits:4}\t{}", location.display())?;
+          }
+        }
+        let path = state.var("PATH").unwrap_or_default();
+        for name in args {
+          if name.parse::<Builtin>().is_err() && state.path_index.lookup(&name, &path).is_none() {
+            return Err(anyhow!("{name}: not found"));
+          }
+        }
+      }
+      // Running a command is handled by `Command::run`, this only describes them
+      Builtin::Command => {
+        let verbose = args.first().is_some_and(|arg| arg == "-V");
+        let mut found = true;
+        for name in args.iter().skip(1) {
+          match state.resolve(name) {
+            Resolution::Alias(value) if verbose => {
+              writeln!(stdout, "{name} is aliased to `{value}'")?
+            }
+            Resolution::Alias(value) => writeln!(stdout, "alias {name}={}", single_quote(&value))?,
+            Resolution::Keyword if verbose => writeln!(stdout, "{name} is a shell keyword")?,
+            Resolution::Keyword => writeln!(stdout, "{name}")?,
+            Resolution::Function(_) if verbose => writeln!(stdout, "{name} is a function")?,
+            Resolution::Function(_) => writeln!(stdout, "{name}")?,
+            Resolution::Builtin(_) if verbose => writeln!(stdout, "{name} is a shell builtin")?,
+            Resolution::Builtin(_) => writeln!(stdout, "{name}")?,
+            Resolution::File(path) if verbose => writeln!(stdout, "{name} is {}", path.display())?,
+            Resolution::File(path) => writeln!(stdout, "{}", path.display())?,
+            Resolution::NotFound => {
+              if verbose {
+                writeln!(stderr, "command: {name}: not found")?;
+              }
+              found = false;
+            }
+          }
+        }
+        if !found {
+          return Err(Status(1).into());
+        }
+      }
+      Builtin::Alias => {
+        if args.is_empty() {
+          for (name, value) in &state.aliases {
+            writeln!(stdout, "alias {name}={}", single_quote(value))?;
+          }
+        }
+        for arg in args {
+          match arg.split_once('=') {
+            Some((name, value)) => _ = state.aliases.insert(name.to_owned(), value.to_owned()),
+            None => match state.aliases.get(&arg) {
+              Some(value) => writeln!(stdout, "alias {arg}={}", single_quote(value))?,
+              None => return Err(anyhow!("{arg}: not found")),
+            },
+          }
+        }
+      }
+      Builtin::Unalias => {
+        if args.first().is_some_and(|arg| arg == "-a") {
+          state.aliases.clear();
+        }
+        for name in args.iter().filter(|arg| *arg != "-a") {
+          state.aliases.remove(name).ok_or(anyhow!("{name}: not found"))?;
+        }
+      }
+      // Handled by `Command::run`, which runs the named builtin in its place
+      Builtin::Builtin => {}
+      Builtin::Pushd => {
+        let current = state.cwd()?;
+        match args.first() {
+          Some(dir) => {
+            let dir = tilde_dir(state, dir)?;
+            change_dir(state, Path::new(&dir), false)?;
+          }
+          None => {
+            let top = state.dir_stack.pop().ok_or(anyhow!("no other directory"))?;
+            if let Err(e) = change_dir(state, &top, false) {
+              state.dir_stack.push(top);
+              return Err(e);
+            }
+          }
+        }
+        state.dir_stack.push(current);
+        writeln!(stdout, "{}", dirs(state)?.join(" "))?;
+      }
+      Builtin::Popd => {
+        let top = state.dir_stack.last().ok_or(anyhow!("directory stack empty"))?.clone();
+        change_dir(state, &top, false)?;
+        state.dir_stack.pop();
+        writeln!(stdout, "{}", dirs(state)?.join(" "))?;
+      }
+      Builtin::Getopts => {
+        let [optstring, name, rest @ ..] = args.as_slice() else {
+          return Err(Usage(String::new()).into());
+        };
+        let args = if rest.is_empty() { &state.positional[..] } else { rest };
+        // A leading `:` reports errors through `name` and `$OPTARG` instead of printing them
+        let (silent, optstring) =
+          optstring.strip_prefix(':').map_or((false, &optstring[..]), |o| (true, o));
+        let optind = state.var("OPTIND").unwrap_or_default();
+        let mut index = optind.parse::<usize>().unwrap_or(1).max(1);
+        let mut offset = match &state.getopts_position {
+          (last, offset) if *last == optind => *offset,
+          _ => 0,
+        };
+
+        let arg = args.get(index - 1).filter(|arg| offset > 0 || arg.len() > 1);
+        let Some(arg) = arg.filter(|arg| offset > 0 || arg.starts_with('-')) else {
+          state.set_var(name, "?".to_owned())?;
+          return Err(Status(1).into());
+        };
+        if offset == 0 && arg == "--" {
+          state.set_var("OPTIND", (index + 1).to_string())?;
+          state.set_var(name, "?".to_owned())?;
+          return Err(Status(1).into());
+        }
+        offset = offset.max(1);
+
+        let option = arg[offset..].chars().next().unwrap_or_default();
+        offset += option.len_utf8();
+        let spec = optstring.find(option).filter(|_| option != ':');
+        let takes_arg = spec.is_some_and(|i| optstring[i + 1..].starts_with(':'));
+        let mut value = None;
+        let mut result = option.to_string();
+        if spec.is_none() {
+          result = "?".to_owned();
+          match silent {
+            true => value = Some(option.to_string()),
+            false => writeln!(stderr, "getopts: illegal option -- {option}")?,
+          }
+        } else if takes_arg {
+          if offset < arg.len() {
+            value = Some(arg[offset..].to_owned());
+          } else if let Some(next) = args.get(index) {
+            value = Some(next.clone());
+            index += 1;
+          } else if silent {
+            result = ":".to_owned();
+            value = Some(option.to_string());
+          } else {
+            result = "?".to_owned();
+            writeln!(stderr, "getopts: option requires an argument -- {option}")?;
+          }
+          offset = arg.len();
+        }
+        if offset >= arg.len() {
+          index += 1;
+          offset = 0;
+        }
+
+        match value {
+          Some(value) => state.set_var("OPTARG", value)?,
+          None => _ = state.vars.remove("OPTARG"),
+        }
+        state.set_var(name, result)?;
+        state.set_var("OPTIND", index.to_string())?;
+        state.getopts_position = (index.to_string(), offset);
       }
+      Builtin::Break | Builtin::Continue => {
+        let count = match args.first() {
+          Some(n) => n.parse::<usize>().map_err(|_| anyhow!("{n}: numeric argument required"))?,
+          None => 1,
+        };
+        if count == 0 {
+          return Err(anyhow!("{count}: loop count out of range"));
+        }
+        if state.loop_depth == 0 {
+          writeln!(stderr, "{self}: only meaningful in a `while', `until' or `select' loop")?;
+          return Ok(());
+        }
+        // Leaving more loops than there are leaves them all
+        let count = count.min(state.loop_depth);
+        state.control_flow = match self {
+          Builtin::Break => ControlFlow::Break(count),
+          _ => ControlFlow::Continue(count),
+        };
+      }
+      Builtin::Shift => {
+        let count = match args.first() {
+          Some(n) => n.parse::<usize>().map_err(|_| anyhow!("{n}: numeric argument required"))?,
+          None => 1,
+        };
+        if count > state.positional.len() {
+          return Err(anyhow!("{count}: shift count out of range"));
+        }
+        state.positional.drain(..count);
+      }
+      Builtin::Declare => {
+        // The attributes to turn on with `-` and off with `+`
+        let (mut on, mut off) = (Variable::default(), Variable::default());
+        let (mut print, mut array) = (false, false);
+        let mut args = args.as_slice();
+        while let Some(arg) = args.first().filter(|arg| arg.len() > 1) {
+          let attributes = match &arg[..1] {
+            "-" => &mut on,
+            "+" => &mut off,
+            _ => break,
+          };
+          for flag in arg[1..].chars() {
+            match flag {
+              'i' => attributes.integer = true,
+              'r' => attributes.readonly = true,
+              'x' => attributes.exported = true,
+              'a' => array = true,
+              'p' => print = true,
+              _ => return Err(Usage(format!("-{flag}: invalid option")).into()),
+            }
+          }
+          args = &args[1..];
+        }
+
+        if args.is_empty() {
+          let filtered = on.integer || on.readonly || on.exported || array;
+          for (name, var) in &state.vars {
+            if !filtered && !print {
+              writeln!(stdout, "{}", assignment(name, var))?;
+            } else if (!on.integer || var.integer)
+              && (!on.readonly || var.readonly)
+              && (!on.exported || var.exported)
+              && (!array || var.array.is_some())
+            {
+              writeln!(stdout, "{}", declaration(name, var))?;
+            }
+          }
+        }
+        for arg in args {
+          let (name, value) =
+            arg.split_once('=').map_or((arg.as_str(), None), |(n, v)| (n, Some(v)));
+          if !is_name(name) {
+            return Err(anyhow!("`{arg}': not a valid identifier"));
+          }
+          if print {
+            let var = state.vars.get(name).ok_or(anyhow!("{name}: not found"))?;
+            writeln!(stdout, "{}", declaration(name, var))?;
+            continue;
+          }
+          let var = state.vars.entry(name.to_owned()).or_default();
+          if var.readonly && (value.is_some() || off.readonly || on.integer || off.integer) {
+            return Err(anyhow!("{name}: readonly variable"));
+          }
+          var.integer = (var.integer || on.integer) && !off.integer;
+          var.exported = (var.exported || on.exported) && !off.exported;
+          if array && var.array.is_none() {
+            let value = std::mem::take(&mut var.value);
+            var.array = Some(BTreeMap::from_iter((!value.is_empty()).then_some((0, value))));
+          }
+          if let Some(value) = value {
+            state.set_var(name, value.to_owned())?;
+          }
+          if on.readonly {
+            state.vars.entry(name.to_owned()).or_default().readonly = true;
+          }
+        }
+      }
+      Builtin::Let => {
+        if args.is_empty() {
+          return Err(anyhow!("expression expected"));
+        }
+        let mut value = 0;
+        for arg in &args {
+          value = arith::eval(arg, state)?;
+        }
+        // Like a C condition, a zero result is false
+        if value == 0 {
+          return Err(Status(1).into());
+        }
+      }
+      Builtin::Jobs => {
+        let (mut long, mut pids) = (false, false);
+        for arg in &args {
+          match arg.as_str() {
+            "-l" => long = true,
+            "-p" => pids = true,
+            _ => return Err(Usage(format!("{arg}: invalid option")).into()),
+          }
+        }
+        state.jobs.update();
+        let colored = color::enabled(state, stdout.is_terminal());
+        for (i, job) in state.jobs.0.iter().enumerate() {
+          let mark = state.jobs.mark(i);
+          // Padded before coloring, as the escapes take up no room
+          let status = format!("{:<24}", job.status.to_string());
+          let status = match job.status {
+            JobStatus::Running => color::paint(status, Color::Yellow, colored),
+            JobStatus::Done(0) => color::paint(status, Color::Green, colored),
+            JobStatus::Done(_) => color::paint(status, Color::Red, colored),
+          };
+          if pids {
+            writeln!(stdout, "{}", job.pid)?;
+          } else if long {
+            writeln!(stdout, "[{}]{mark} {:>5} {status}{}", job.id, job.pid, job.command)?;
+          } else {
+            writeln!(stdout, "[{}]{mark}  {status}{}", job.id, job.command)?;
+          }
+        }
+        // Finished jobs are reported once
+        state.jobs.0.retain(|job| job.status == JobStatus::Running);
+      }
+      Builtin::Disown => {
+        let (mut all, mut keep) = (false, false);
+        let mut args = args.as_slice();
+        while let Some(arg) = args.first().filter(|arg| arg.starts_with('-') && arg.len() > 1) {
+          for flag in arg[1..].chars() {
+            match flag {
+              'a' => all = true,
+              'h' => keep = true,
+              _ => return Err(Usage(format!("-{flag}: invalid option")).into()),
+            }
+          }
+          args = &args[1..];
+        }
+
+        let mut indices = vec![];
+        if all {
+          indices.extend(0..state.jobs.0.len());
+        } else if args.is_empty() {
+          indices.push(state.jobs.find("%+").ok_or(anyhow!("current: no such job"))?);
+        }
+        for arg in args {
+          indices.push(state.jobs.find(arg).ok_or(anyhow!("{arg}: no such job"))?);
+        }
+        if keep {
+          for i in indices {
+            state.jobs.0[i].no_hangup = true;
+          }
+        } else {
+          let disowned = indices.iter().map(|&i| &state.jobs.0[i]);
+          let running = disowned.filter(|job| job.status == JobStatus::Running);
+          state.jobs.1.extend(running.map(|job| job.pid).collect::<Vec<_>>());
+          let mut i = 0;
+          state.jobs.0.retain(|_| {
+            i += 1;
+            !indices.contains(&(i - 1))
+          });
+        }
+      }
+      Builtin::Wait => {
+        // `-n` waits for just the first of the jobs to finish, rather than all of them
+        let (next, ids) = match args.split_first() {
+          Some((flag, ids)) if flag == "-n" => (true, ids),
+          _ => (false, &args[..]),
+        };
+        if let Some(arg) = ids.iter().find(|arg| arg.starts_with('-')) {
+          return Err(Usage(format!("{arg}: invalid option")).into());
+        }
+        let mut status = 0;
+        let mut indices = vec![];
+        for id in ids {
+          match state.jobs.find(id) {
+            Some(index) => indices.push(index),
+            None => {
+              match id.starts_with('%') {
+                true => writeln!(stderr, "wait: {id}: no such job")?,
+                false => writeln!(stderr, "wait: pid {id} is not a child of this shell")?,
+              }
+              status = 127;
+            }
+          }
+        }
+        if ids.is_empty() {
+          indices = (0..state.jobs.0.len()).collect();
+        }
+        if next && indices.is_empty() {
+          return Err(Status(127).into());
+        }
+        let groups = match next {
+          true => vec![indices],
+          false => indices.into_iter().map(|index| vec![index]).collect(),
+        };
+        let mut waited = vec![];
+        for group in groups {
+          let Some(index) = state.jobs.wait_any(&group) else {
+            // Interrupted, as by a trapped signal
+            return Err(Status(128 + libc::SIGINT).into());
+          };
+          // Waiting for every job succeeds whatever they did
+          if let JobStatus::Done(job_status) = state.jobs.0[index].status
+            && (next || !ids.is_empty())
+          {
+            status = job_status;
+          }
+          waited.push(index);
+        }
+        // Jobs that have been waited for are done with, and aren't reported
+        let mut i = 0;
+        state.jobs.0.retain(|_| {
+          i += 1;
+          !waited.contains(&(i - 1))
+        });
+        if status != 0 {
+          return Err(Status(status).into());
+        }
+      }
+      Builtin::Mapfile => {
+        let (mut strip, mut count) = (false, None);
+        let mut args = args.iter();
+        let mut name = None;
+        while let Some(arg) = args.next() {
+          match arg.as_str() {
+            "-t" => strip = true,
+            "-n" => {
+              let n =
+                args.next().ok_or_else(|| Usage("-n: option requires an argument".to_owned()))?;
+              let n = n.parse::<usize>().map_err(|_| anyhow!("{n}: invalid line count"))?;
+              // A count of 0 means all of them
+              count = Some(n).filter(|n| *n > 0);
+            }
+            _ if arg.starts_with('-') => {
+              return Err(Usage(format!("{arg}: invalid option")).into());
+            }
+            _ => name = Some(arg.as_str()),
+          }
+        }
+        let name = name.unwrap_or("MAPFILE");
+        if !is_name(name) {
+          return Err(anyhow!("`{name}': not a valid identifier"));
+        }
+
+        let fd = stdin.as_ref().map_or(libc::STDIN_FILENO, AsRawFd::as_raw_fd);
+        let lines = read_lines(&mut duplicate(fd)?, count, !strip)?;
+        state.set_array(name, lines)?;
+      }
+      Builtin::Read => {
+        let (mut raw, mut silent) = (false, false);
+        let (mut prompt, mut timeout) = (None, None);
+        let mut delimiter = b'\n';
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+          match arg.as_str() {
+            "-r" => raw = true,
+            "-s" => silent = true,
+            "-d" | "-p" | "-t" => {
+              let value =
+                args.next().ok_or_else(|| Usage(format!("{arg}: option requires an argument")))?;
+              match arg.as_str() {
+                // The line ends at the first character of `-d`'s argument, or a NUL if it's empty
+                "-d" => delimiter = value.bytes().next().unwrap_or(0),
+                "-p" => prompt = Some(value),
+                _ => {
+                  let seconds = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| *seconds >= 0.0)
+                    .ok_or_else(|| anyhow!("{value}: invalid timeout specification"))?;
+                  timeout = Some(Instant::now() + Duration::from_secs_f64(seconds));
+                }
+              }
+            }
+            _ => return Err(Usage(format!("{arg}: invalid option")).into()),
+          }
+        }
+        let names = args.map(String::as_str).collect::<Vec<_>>();
+        if let Some(name) = names.iter().find(|name| !is_name(name)) {
+          return Err(anyhow!("`{name}': not a valid identifier"));
+        }
+
+        let fd = stdin.as_ref().map_or(libc::STDIN_FILENO, AsRawFd::as_raw_fd);
+        let mut input = duplicate(fd)?;
+        // A terminal gives whole lines, echoed unless they're secret, whatever mode the line
+        // editor left it in
+        let terminal = unsafe { libc::isatty(fd) } == 1;
+        let saved = terminal.then(|| unsafe {
+          let mut saved = std::mem::zeroed();
+          libc::tcgetattr(fd, &mut saved);
+          let mut line = saved;
+          line.c_lflag |= libc::ICANON | libc::ECHO;
+          if silent {
+            line.c_lflag &= !libc::ECHO;
+          }
+          libc::tcsetattr(fd, libc::TCSANOW, &line);
+          saved
+        });
+        if let Some(prompt) = prompt.filter(|_| terminal) {
+          write!(stderr, "{prompt}")?;
+          stderr.flush()?;
+        }
+
+        // Read a byte at a time, so that nothing past the line is used up. Each byte notes whether
+        // a backslash escaped it.
+        let mut line: Vec<(u8, bool)> = vec![];
+        let mut byte = [0];
+        let mut escaped = false;
+        let res = loop {
+          if let Some(deadline) = timeout {
+            let mut poll = libc::pollfd {
+              fd,
+              events: libc::POLLIN,
+              revents: 0,
+            };
+            let wait = deadline.saturating_duration_since(Instant::now()).as_millis();
+            match unsafe { libc::poll(&mut poll, 1, wait.min(i32::MAX as u128) as i32) } {
+              0 => break Err(Status(128 + libc::SIGALRM)),
+              // A child finishing interrupts the wait, which goes on for what's left of it
+              ..0 => continue,
+              _ => {}
+            }
+          }
+          match input.read(&mut byte) {
+            Ok(0) => break Ok(false),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break Ok(false),
+          }
+          match byte[0] {
+            // An escaped new line carries on to the next one
+            b'\n' if escaped => escaped = false,
+            byte if byte == delimiter && !escaped => break Ok(true),
+            b'\\' if !raw && !escaped => escaped = true,
+            byte => line.push((byte, std::mem::take(&mut escaped))),
+          }
+        };
+        if let Some(saved) = saved {
+          unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+          if silent {
+            writeln!(stderr)?;
+          }
+        }
+        // A timeout leaves the variables as they were
+        let complete = res?;
+        if state.options.igncr && line.last() == Some(&(b'\r', false)) {
+          line.pop();
+        }
+
+        match names.is_empty() {
+          true => {
+            let bytes = line.iter().map(|(byte, _)| *byte).collect::<Vec<_>>();
+            state.set_var("REPLY", String::from_utf8_lossy(&bytes).into_owned())?;
+          }
+          false => {
+            let ifs = state.var("IFS").unwrap_or_else(|| " \t\n".to_owned());
+            let fields = read_fields(&line, ifs.as_bytes(), names.len());
+            for (i, name) in names.iter().enumerate() {
+              state.set_var(name, fields.get(i).cloned().unwrap_or_default())?;
+            }
+          }
+        }
+        // The input ending before a new line is a failure, though what there was is kept
+        if !complete {
+          return Err(Status(1).into());
+        }
+      }
+      Builtin::Complete => {
+        let mut spec = CompletionSpec::default();
+        let (mut print, mut remove) = (false, false);
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+          match arg.as_str() {
+            "-W" => {
+              let words =
+                args.next().ok_or_else(|| Usage("-W: option requires an argument".to_owned()))?;
+              spec.words.extend(tokenize_and_expand(words, state)?);
+            }
+            "-d" => spec.dirs = true,
+            "-f" => spec.files = true,
+            "-p" => print = true,
+            "-r" => remove = true,
+            _ => return Err(Usage(format!("{arg}: invalid option")).into()),
+          }
+        }
+        let names = args.collect::<Vec<_>>();
+
+        if remove {
+          match names.is_empty() {
+            true => state.completions.clear(),
+            false => names.iter().for_each(|name| _ = state.completions.remove(*name)),
+          }
+        } else if print || names.is_empty() {
+          for (name, spec) in &state.completions {
+            if names.is_empty() || names.contains(&name) {
+              writeln!(stdout, "complete {spec}{name}")?;
+            }
+          }
+        } else {
+          for name in names {
+            state.completions.insert(name.clone(), spec.clone());
+          }
+        }
+      }
+      Builtin::Shopt => {
+        let (mut set, mut unset, mut print, mut quiet, mut set_options) =
+          (false, false, false, false, false);
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+          for flag in arg[1..].chars() {
+            match flag {
+              's' => set = true,
+              'u' => unset = true,
+              'p' => print = true,
+              'q' => quiet = true,
+              // `-o` works on the options of `set -o` instead
+              'o' => set_options = true,
+              _ => return Err(Usage(format!("-{flag}: invalid option")).into()),
+            }
+          }
+        }
+        if set && unset {
+          return Err(anyhow!("cannot set and unset shell options simultaneously"));
+        }
+        let names = args.map(String::as_str).collect::<Vec<_>>();
+        if let Some(name) = names.iter().find(|name| !OPTIONS.contains(&(name, set_options))) {
+          return Err(anyhow!("{name}: invalid shell option name"));
+        }
+
+        if (set || unset) && !names.is_empty() {
+          for name in names {
+            if let Some(on) = state.options.get_mut(name) {
+              *on = set;
+            }
+          }
+          return Ok(());
+        }
+        let explicit = !names.is_empty();
+        let names = match explicit {
+          true => names,
+          false => {
+            OPTIONS.iter().filter(|(_, set)| *set == set_options).map(|(name, _)| *name).collect()
+          }
+        };
+        let mut all_on = true;
+        for name in names {
+          let on = state.options.get(name) == Some(true);
+          all_on &= on;
+          // Without names, `-s` and `-u` list only the options that are on or off
+          if quiet || set && !on || unset && on {
+            continue;
+          }
+          match (print, set_options) {
+            (true, false) => writeln!(stdout, "shopt {} {name}", if on { "-s" } else { "-u" })?,
+            (true, true) => writeln!(stdout, "set {}o {name}", if on { '-' } else { '+' })?,
+            (false, _) => writeln!(stdout, "{name:<15}\t{}", if on { "on" } else { "off" })?,
+          }
+        }
+        if explicit && !all_on {
+          return Err(Status(1).into());
+        }
+      }
+      Builtin::Fc => {
+        let (mut list, mut numbers, mut reverse, mut substitute) = (false, true, false, false);
+        let mut editor = None;
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next_if(|arg| {
+          arg.starts_with('-')
+            && arg.len() > 1
+            && !arg[1..].starts_with(|c: char| c.is_ascii_digit())
+        }) {
+          for flag in arg[1..].chars() {
+            match flag {
+              'l' => list = true,
+              'n' => numbers = false,
+              'r' => reverse = true,
+              's' => substitute = true,
+              'e' => {
+                editor = Some(
+                  args.next().ok_or_else(|| Usage("-e: option requires an argument".to_owned()))?,
+                )
+              }
+              _ => return Err(Usage(format!("-{flag}: invalid option")).into()),
+            }
+          }
+        }
+        // An interactive shell has already put this `fc` in the history, which isn't what it's
+        // about
+        let past = state.history.len().saturating_sub(state.interactive as usize);
+        let history = &state.history[..past];
+
+        let command = if substitute || editor.is_some_and(|editor| editor == "-") {
+          let mut replacements = vec![];
+          while let Some((from, to)) = args.peek().and_then(|arg| arg.split_once('=')) {
+            replacements.push((from, to));
+            args.next();
+          }
+          let index = match args.next() {
+            Some(spec) => history_entry(history, spec)?,
+            None => history.len().checked_sub(1).ok_or(anyhow!("no command found"))?,
+          };
+          let mut command = history[index].clone();
+          for (from, to) in replacements {
+            command = command.replacen(from, to, 1);
+          }
+          command
+        } else {
+          // Listing defaults to the last 16 commands, and editing to the last one
+          let first = args.next().map_or(if list { "-16" } else { "-1" }, String::as_str);
+          let last = args.next().map_or(if list { "-1" } else { first }, String::as_str);
+          let (first, last) = match (history_entry(history, first), history_entry(history, last)) {
+            // Counting back further than the history goes lists all of it
+            (Err(_), Ok(last)) if list && first.starts_with('-') => (0, last),
+            (first, last) => (first?, last?),
+          };
+          let mut range = (first.min(last)..=first.max(last)).collect::<Vec<_>>();
+          if reverse != (first > last) {
+            range.reverse();
+          }
+
+          if list {
+            for i in range {
+              match numbers {
+                true => writeln!(stdout, "{}\t {}", i + 1, history[i])?,
+                false => writeln!(stdout, "\t {}", history[i])?,
+              }
+            }
+            return Ok(());
+          }
+
+          let editor = editor
+            .cloned()
+            .or_else(|| state.var("FCEDIT"))
+            .or_else(|| state.var("EDITOR"))
+            .unwrap_or_else(|| "vi".to_owned());
+          let commands = range.iter().map(|i| history[*i].as_str()).collect::<Vec<_>>();
+          edit_text(state, &editor, "fc", &commands.join("\n"))?
+        };
+
+        // The command run replaces `fc` in the history, and is echoed like history expansion
+        writeln!(stderr, "{command}")?;
+        state.history.truncate(past);
+        state.history_times.truncate(past);
+        state.push_history(command.clone());
+        crate::run_line(state, &command);
+        if state.status != 0 {
+          return Err(Status(state.status).into());
+        }
+      }
+      Builtin::Dirs => match args.first().map(String::as_str) {
+        None => writeln!(stdout, "{}", dirs(state)?.join(" "))?,
+        Some("-v") => {
+          for (i, dir) in dirs(state)?.iter().enumerate() {
+            writeln!(stdout, "{i:2}  {dir}")?;
+          }
+        }
+        Some("-c") => state.dir_stack.clear(),
+        Some(arg) => return Err(Usage(format!("{arg}: invalid option")).into()),
+      },
     }
     Ok(())
   }
@@ -153,7 +2256,7 @@ impl Builtin {
 impl FromStr for Builtin {
   type Err = ();
   fn from_str(command: &str) -> Result<Self, Self::Err> {
-    use Builtin::*;
+    use crate::builtin::Builtin::*;
     let command = command.trim();
     match command {
       "exit" => Ok(Exit),
@@ -162,6 +2265,34 @@ impl FromStr for Builtin {
       "pwd" => Ok(Pwd),
       "cd" => Ok(Cd),
       "history" => Ok(History),
+      "exec" => Ok(Exec),
+      "set" => Ok(Set),
+      "export" => Ok(Export),
+      "umask" => Ok(Umask),
+      "trap" => Ok(Trap),
+      "hash" => Ok(Hash),
+      "command" => Ok(Command),
+      "alias" => Ok(Alias),
+      "unalias" => Ok(Unalias),
+      "builtin" => Ok(Builtin),
+      "pushd" => Ok(Pushd),
+      "popd" => Ok(Popd),
+      "dirs" => Ok(Dirs),
+      "getopts" => Ok(Getopts),
+      "shift" => Ok(Shift),
+      "declare" | "typeset" => Ok(Declare),
+      "let" => Ok(Let),
+      "jobs" => Ok(Jobs),
+      "disown" => Ok(Disown),
+      "printf" => Ok(Printf),
+      "mapfile" | "readarray" => Ok(Mapfile),
+      "complete" => Ok(Complete),
+      "fc" => Ok(Fc),
+      "shopt" => Ok(Shopt),
+      "read" => Ok(Read),
+      "wait" => Ok(Wait),
+      "break" => Ok(Break),
+      "continue" => Ok(Continue),
       _ => Err(()),
     }
   }
@@ -0,0 +1,27 @@
+use std::fmt::Display;
+
+use crate::builtin::State;
+
+/// The ANSI foreground colors builtins highlight their output with
+#[derive(Clone, Copy, Debug)]
+pub enum Color {
+  Red = 31,
+  Green = 32,
+  Yellow = 33,
+  Magenta = 35,
+  Cyan = 36,
+}
+
+/// Whether output should be colored: only when it goes to a `terminal`, so files and pipes get
+/// plain text, and `$NO_COLOR` isn't set to anything
+pub fn enabled(state: &State, terminal: bool) -> bool {
+  terminal && state.var("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// `text` in `color` if `enabled`, otherwise as it is
+pub fn paint(text: impl Display, color: Color, enabled: bool) -> String {
+  match enabled {
+    true => format!("\x1B[{}m{text}\x1B[0m", color as u8),
+    false => text.to_string(),
+  }
+}
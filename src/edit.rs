@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+
+/// How many kills the kill ring keeps before dropping the oldest
+const KILL_RING_SIZE: usize = 16;
+
+/// The text taken out of lines by Ctrl-U, Ctrl-K and Ctrl-W, newest first, for Ctrl-Y to put back
+#[derive(Debug, Default)]
+pub struct KillRing {
+  kills: VecDeque<String>,
+}
+
+impl KillRing {
+  /// Saves `text` as the newest kill, unless there's nothing to save
+  pub fn push(&mut self, text: String) {
+    if text.is_empty() {
+      return;
+    }
+    self.kills.push_front(text);
+    self.kills.truncate(KILL_RING_SIZE);
+  }
+
+  /// The newest kill
+  pub fn yank(&self) -> Option<&str> {
+    self.kills.front().map(String::as_str)
+  }
+
+  /// Moves the newest kill to the back, for Meta-Y, and gives the one that's newest now
+  pub fn rotate(&mut self) -> Option<&str> {
+    self.kills.rotate_left(1.min(self.kills.len()));
+    self.yank()
+  }
+}
+
+/// Swaps the character before the cursor with the one under it, or the last two at the end of the
+/// line, for Ctrl-T. Gives where the swapped pair starts, the cursor going after it, or `None` if
+/// there aren't two characters to swap.
+pub fn transpose(input: &mut [char], cursor_position: usize) -> Option<usize> {
+  if input.len() < 2 || cursor_position == 0 {
+    return None;
+  }
+  let start = cursor_position.min(input.len() - 1) - 1;
+  input.swap(start, start + 1);
+  Some(start)
+}
+
+/// Where the word before the cursor starts, for Meta-B: back over anything but letters and digits,
+/// then over those
+pub fn word_start(input: &[char], cursor_position: usize) -> usize {
+  let before = &input[..cursor_position];
+  let end = before.iter().rposition(|c| c.is_alphanumeric()).map_or(0, |i| i + 1);
+  before[..end].iter().rposition(|c| !c.is_alphanumeric()).map_or(0, |i| i + 1)
+}
+
+/// Where the word the cursor is in or before ends, for Meta-F: on over anything but letters and
+/// digits, then over those
+pub fn word_end(input: &[char], cursor_position: usize) -> usize {
+  let after = &input[cursor_position..];
+  let start = after.iter().position(|c| c.is_alphanumeric()).unwrap_or(after.len());
+  let len = after[start..].iter().position(|c| !c.is_alphanumeric()).unwrap_or(after.len() - start);
+  cursor_position + start + len
+}
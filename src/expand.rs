@@ -0,0 +1,567 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::iter::once;
+use std::os::fd::FromRawFd;
+
+use anyhow::anyhow;
+
+use crate::arith;
+use crate::builtin::State;
+use crate::glob;
+use crate::options::ShellOptions;
+use crate::split::{self, Segment, Token, Word};
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// A field's text along with which of its characters were quoted, so that pathname expansion
+/// leaves them alone
+#[derive(Default)]
+struct Field {
+  text: String,
+  quoted: Vec<bool>,
+}
+
+impl Field {
+  fn push(&mut self, c: char, quoted: bool) {
+    self.text.push(c);
+    self.quoted.push(quoted);
+  }
+
+  /// The field as a glob pattern, with quoted characters escaped to only match themselves
+  fn pattern(&self) -> String {
+    let chars = self.text.chars().zip(&self.quoted);
+    chars
+      .map(|(c, quoted)| if *quoted { glob::escape(&c.to_string()) } else { c.to_string() })
+      .collect()
+  }
+
+  /// Expands the field into the paths its unquoted pattern characters match. A pattern matching
+  /// nothing stays as it is, unless `nullglob` removes it or `failglob` makes it an error.
+  fn expand_paths(self, options: &ShellOptions) -> anyhow::Result<Vec<String>> {
+    let pattern = self.pattern();
+    if !glob::has_magic(&pattern, options.extglob) {
+      return Ok(vec![self.text]);
+    }
+    match glob::expand_paths(&pattern, options.extglob) {
+      paths if !paths.is_empty() => Ok(paths),
+      _ if options.failglob => Err(anyhow!("no match: {}", self.text)),
+      _ if options.nullglob => Ok(vec![]),
+      _ => Ok(vec![self.text]),
+    }
+  }
+}
+
+/// Splits unquoted expansion results into fields on the characters of `$IFS`
+struct FieldSplitter<'a> {
+  ifs: &'a str,
+  fields: Vec<Field>,
+  field: Field,
+  /// Whether `field` exists, even if empty (e.g. from `""`)
+  in_field: bool,
+  /// Whether the last field was ended by IFS whitespace, which absorbs a following delimiter
+  after_space: bool,
+}
+
+impl<'a> FieldSplitter<'a> {
+  fn new(ifs: &'a str) -> Self {
+    Self {
+      ifs,
+      fields: vec![],
+      field: Field::default(),
+      in_field: false,
+      after_space: false,
+    }
+  }
+
+  fn push_str(&mut self, s: &str, quoted: bool) {
+    s.chars().for_each(|c| self.field.push(c, quoted));
+    self.in_field = true;
+    self.after_space = false;
+  }
+
+  fn push_split(&mut self, s: &str) {
+    for c in s.chars() {
+      if !self.ifs.contains(c) {
+        self.field.push(c, false);
+        self.in_field = true;
+        self.after_space = false;
+      } else if c.is_whitespace() {
+        if self.in_field {
+          self.end_field();
+          self.after_space = true;
+        }
+      } else {
+        if self.in_field || !self.after_space {
+          self.end_field();
+        }
+        self.after_space = false;
+      }
+    }
+  }
+
+  /// Pushes each of `values` as its own field, like `"$@"`, joining the first and last to what
+  /// comes before and after
+  fn push_fields(&mut self, values: &[String], quoted: bool) {
+    for (i, value) in values.iter().enumerate() {
+      if i > 0 && (quoted || self.in_field) {
+        self.end_field();
+      }
+      match quoted {
+        true => self.push_str(value, true),
+        false => self.push_split(value),
+      }
+    }
+  }
+
+  fn end_field(&mut self) {
+    self.fields.push(std::mem::take(&mut self.field));
+    self.in_field = false;
+  }
+
+  fn end_word(&mut self) {
+    if self.in_field {
+      self.end_field();
+    }
+    self.after_space = false;
+  }
+}
+
+/// Expands the parameters in `words`, splits the unquoted results into fields and expands fields
+/// with unquoted pattern characters into the paths they match. Unquoted expansions that produce
+/// nothing leave no field behind.
+pub fn expand(words: Vec<Word>, state: &mut State) -> anyhow::Result<Vec<String>> {
+  let ifs = state.var("IFS").unwrap_or_else(|| DEFAULT_IFS.to_owned());
+  let mut splitter = FieldSplitter::new(&ifs);
+  for word in words {
+    for segment in &word.0 {
+      match segment {
+        Segment::Literal(text, quoted) => splitter.push_str(text, *quoted),
+        // Unquoted, `$*` splits into the same fields as `$@`, even when `$IFS` is empty
+        Segment::Param(expr, quoted) if expr == "@" || expr == "*" && !quoted => {
+          splitter.push_fields(&state.positional, *quoted)
+        }
+        // `${name[@]}` is each element, like `$@`
+        Segment::Param(expr, quoted) if whole_array(expr).is_some() => {
+          let name = whole_array(expr).unwrap_or_default();
+          let elements = state.array(name).unwrap_or_default().into_values().collect::<Vec<_>>();
+          splitter.push_fields(&elements, *quoted)
+        }
+        Segment::Param(expr, true) => splitter.push_str(&expand_param(expr, state)?, true),
+        Segment::Param(expr, false) => splitter.push_split(&expand_param(expr, state)?),
+        Segment::Arith(expr, true) => splitter.push_str(&expand_arith(expr, state)?, true),
+        Segment::Arith(expr, false) => splitter.push_split(&expand_arith(expr, state)?),
+        Segment::Process(command, output) => {
+          splitter.push_str(&expand_process(command, *output, state)?, true)
+        }
+      }
+    }
+    splitter.end_word();
+  }
+  let fields = splitter.fields.into_iter().map(|field| field.expand_paths(&state.options));
+  Ok(fields.collect::<anyhow::Result<Vec<_>>>()?.concat())
+}
+
+/// Splits `line` into words and expands them as the arguments of a command would be, for text
+/// that's a list of words rather than a command, like `complete -W`'s. Operators and redirections
+/// have no place there, so they're errors.
+pub fn tokenize_and_expand(line: &str, state: &mut State) -> anyhow::Result<Vec<String>> {
+  let tokens = split::split(line, &state.options).map_err(|e| anyhow!("{line}: {e}"))?;
+  let words = tokens.into_iter().map(|token| match token {
+    Token::Word(word) => Ok(word),
+    Token::Operator(op) | Token::Redirect(_, op) => {
+      Err(anyhow!("syntax error near unexpected token `{}'", op.replace('\n', "newline")))
+    }
+  });
+  expand(words.collect::<anyhow::Result<_>>()?, state)
+}
+
+pub fn expand_word(word: &Word, state: &mut State) -> anyhow::Result<String> {
+  let mut res = String::new();
+  for segment in &word.0 {
+    match segment {
+      Segment::Literal(text, _) => res.push_str(text),
+      Segment::Param(expr, _) => res.push_str(&expand_param(expr, state)?),
+      Segment::Arith(expr, _) => res.push_str(&expand_arith(expr, state)?),
+      Segment::Process(command, output) => res.push_str(&expand_process(command, *output, state)?),
+    }
+  }
+  Ok(res)
+}
+
+/// Removes the leading `NAME=value` words from `words`, returning the expanded assignments.
+/// The subscript of a `NAME[subscript]=value` is evaluated, leaving the name as `NAME[N]`.
+pub fn expand_assignments(
+  words: &mut Vec<Word>,
+  state: &mut State,
+) -> anyhow::Result<Vec<(String, String)>> {
+  let count = words.iter().take_while(|word| assignment(word).is_some()).count();
+  let mut assignments = vec![];
+  for (name, value) in words.drain(..count).filter_map(|word| assignment(&word)) {
+    let name = match split_subscript(&name[split_name(&name).0.len()..]) {
+      (Some(subscript), _) => {
+        let base = split_name(&name).0;
+        format!("{base}[{}]", index(base, subscript, state)?)
+      }
+      (None, _) => name,
+    };
+    assignments.push((name, expand_word(&value, state)?));
+  }
+  Ok(assignments)
+}
+
+/// Splits a `NAME=value` or `NAME[subscript]=value` word into the name and the word making up
+/// the value
+pub fn assignment(word: &Word) -> Option<(String, Word)> {
+  let (Segment::Literal(text, false), rest) = word.0.split_first()? else {
+    return None;
+  };
+  let (name, value) = text.split_once('=')?;
+  let base = match split_subscript(&name[split_name(name).0.len()..]) {
+    (Some(_), "") => split_name(name).0,
+    _ => name,
+  };
+  if !is_name(base) {
+    return None;
+  }
+  let value = once(Segment::Literal(value.to_owned(), false)).chain(rest.iter().cloned());
+  Some((name.to_owned(), Word(value.collect())))
+}
+
+/// Expands `word` as a pattern, escaping quoted parts so they only match literally
+fn expand_pattern(word: &Word, state: &mut State) -> anyhow::Result<String> {
+  let mut res = String::new();
+  for segment in &word.0 {
+    match segment {
+      Segment::Literal(text, false) => res.push_str(text),
+      Segment::Literal(text, true) => res.push_str(&glob::escape(text)),
+      Segment::Param(expr, false) => res.push_str(&expand_param(expr, state)?),
+      Segment::Param(expr, true) => res.push_str(&glob::escape(&expand_param(expr, state)?)),
+      Segment::Arith(expr, _) => res.push_str(&expand_arith(expr, state)?),
+      Segment::Process(command, output) => {
+        res.push_str(&glob::escape(&expand_process(command, *output, state)?))
+      }
+    }
+  }
+  Ok(res)
+}
+
+pub fn is_name(s: &str) -> bool {
+  s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+    && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits the leading parameter name off `expr`
+fn split_name(expr: &str) -> (&str, &str) {
+  let name_len = match expr.chars().next() {
+    Some('?' | '#' | '@' | '*' | '$' | '!') => 1,
+    Some(c) if c.is_ascii_digit() => expr.find(|c: char| !c.is_ascii_digit()).unwrap_or(expr.len()),
+    _ => expr.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(expr.len()),
+  };
+  expr.split_at(name_len)
+}
+
+/// Splits the `[subscript]` of an array element off the start of what follows a name
+fn split_subscript(op: &str) -> (Option<&str>, &str) {
+  match op.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+    Some((subscript, op)) => (Some(subscript), op),
+    None => (None, op),
+  }
+}
+
+/// The name of the array a `name[@]` expression expands every element of
+fn whole_array(expr: &str) -> Option<&str> {
+  expr.strip_suffix("[@]").filter(|name| is_name(name))
+}
+
+/// Evaluates the arithmetic `subscript` of the array `name` into an index, counting back from the
+/// end if negative
+fn index(name: &str, subscript: &str, state: &mut State) -> anyhow::Result<usize> {
+  let word = split::word(subscript, &state.options)
+    .map_err(|_| anyhow!("{subscript}: bad array subscript"))?;
+  let index = arith::eval(&expand_word(&word, state)?, state)?;
+  let end = state.array(name).and_then(|array| array.last_key_value().map(|(i, _)| i + 1));
+  let index = if index < 0 { index + end.unwrap_or(0) as i64 } else { index };
+  usize::try_from(index).map_err(|_| anyhow!("{name}[{subscript}]: bad array subscript"))
+}
+
+/// The elements of the array `name` that `subscript` picks: all of them for `@` or `*`, or the
+/// one at the index it evaluates to
+fn elements(name: &str, subscript: &str, state: &mut State) -> anyhow::Result<Vec<String>> {
+  if subscript == "@" || subscript == "*" {
+    return Ok(state.array(name).unwrap_or_default().into_values().collect());
+  }
+  let index = index(name, subscript, state)?;
+  Ok(state.array(name).unwrap_or_default().remove(&index).into_iter().collect())
+}
+
+/// Expands `offset[:length]` of `${VAR:offset:length}`, counting negative values from the end
+fn substring(value: &str, spec: &str) -> anyhow::Result<String> {
+  let parse = |n: &str| match n.trim() {
+    "" => Ok(0),
+    n => n.parse::<i64>().map_err(|_| anyhow!("{n}: syntax error: operand expected")),
+  };
+  let (offset, length) = spec.split_once(':').map_or((spec, None), |(o, l)| (o, Some(l)));
+  let chars = value.chars().collect::<Vec<_>>();
+  let len = chars.len() as i64;
+
+  let offset = parse(offset)?;
+  let start = if offset < 0 { len + offset } else { offset };
+  if !(0..=len).contains(&start) {
+    return Ok(String::new());
+  }
+  let end = match length.map(parse).transpose()? {
+    None => len,
+    Some(length) if length < 0 => len + length,
+    Some(length) => (start + length).min(len),
+  };
+  if end < start {
+    return Err(anyhow!("{}: substring expression < 0", length.unwrap_or_default().trim()));
+  }
+  Ok(chars[start as usize..end as usize].iter().collect())
+}
+
+/// Removes the shortest or longest prefix (`#`) or suffix (`%`) of `value` matching `pattern`
+fn remove_affix(
+  value: &str,
+  operator: char,
+  longest: bool,
+  pattern: &str,
+  extglob: bool,
+) -> String {
+  let bounds = value.char_indices().map(|(i, _)| i).chain(once(value.len()));
+  let mut bounds = bounds.collect::<Vec<_>>();
+  if (operator == '#') == longest {
+    bounds.reverse();
+  }
+  let stripped = match operator {
+    '#' => bounds
+      .into_iter()
+      .find(|&i| glob::matches(pattern, &value[..i], extglob))
+      .map(|i| &value[i..]),
+    _ => bounds
+      .into_iter()
+      .find(|&i| glob::matches(pattern, &value[i..], extglob))
+      .map(|i| &value[..i]),
+  };
+  stripped.unwrap_or(value).to_owned()
+}
+
+/// Splits `s` at the first `/` that isn't quoted or escaped
+fn split_slash(s: &str) -> (&str, Option<&str>) {
+  let mut quote = None;
+  let mut escaped = false;
+  for (i, c) in s.char_indices() {
+    match (quote, c) {
+      _ if escaped => escaped = false,
+      (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+      (Some('\''), _) => {}
+      (_, '\\') => escaped = true,
+      (None, '\'' | '"') => quote = Some(c),
+      (None, '/') => return (&s[..i], Some(&s[i + 1..])),
+      _ => {}
+    }
+  }
+  (s, None)
+}
+
+/// Replaces the longest matches of `pattern` in `value`. `anchor` is `/` to replace every match,
+/// `#`/`%` to only match at the start/end, or anything else to replace the first match.
+fn substitute(
+  value: &str,
+  anchor: Option<char>,
+  pattern: &str,
+  replacement: &str,
+  extglob: bool,
+) -> String {
+  let bounds = value.char_indices().map(|(i, _)| i).chain(once(value.len())).collect::<Vec<_>>();
+  let mut res = String::new();
+  let mut copied = 0;
+
+  for &start in &bounds {
+    if start < copied || (anchor == Some('#') && start > 0) {
+      continue;
+    }
+    let end = match anchor {
+      Some('%') => Some(value.len()).filter(|_| glob::matches(pattern, &value[start..], extglob)),
+      _ => bounds
+        .iter()
+        .rev()
+        .copied()
+        .find(|&end| end > start && glob::matches(pattern, &value[start..end], extglob)),
+    };
+    let Some(end) = end else {
+      continue;
+    };
+    res.push_str(&value[copied..start]);
+    res.push_str(replacement);
+    copied = end;
+    if anchor != Some('/') {
+      break;
+    }
+  }
+
+  res.push_str(&value[copied..]);
+  res
+}
+
+/// Starts `command` with its output, or for `>(command)` its input, connected to a pipe, returning
+/// the `/dev/fd` path of the shell's end. That end stays open until the command it's an argument of
+/// has started, and the process is waited for once its pipeline has finished.
+fn expand_process(command: &str, output: bool, state: &mut State) -> anyhow::Result<String> {
+  let mut fds = [0; 2];
+  if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+    return Err(anyhow!("pipe: {}", io::Error::last_os_error()));
+  }
+  let [reader, writer] = fds;
+  let (ours, theirs, target) = match output {
+    false => (reader, writer, libc::STDOUT_FILENO),
+    true => (writer, reader, libc::STDIN_FILENO),
+  };
+  _ = io::stdout().flush();
+  match unsafe { libc::fork() } {
+    -1 => {
+      let e = io::Error::last_os_error();
+      unsafe {
+        libc::close(reader);
+        libc::close(writer);
+      }
+      Err(anyhow!("fork: {e}"))
+    }
+    0 => {
+      unsafe {
+        libc::dup2(theirs, target);
+        libc::close(reader);
+        libc::close(writer);
+      }
+      // The pipes of other substitutions are the shell's, and would keep them from ending
+      state.substitution_fds.clear();
+      state.substitutions.clear();
+      state.jobs = Default::default();
+      state.interactive = false;
+      crate::run_line(state, command);
+      _ = io::stdout().flush();
+      unsafe { libc::_exit(state.status) };
+    }
+    pid => {
+      unsafe { libc::close(theirs) };
+      state.substitutions.push(pid);
+      state.substitution_fds.push(unsafe { File::from_raw_fd(ours) });
+      Ok(format!("/dev/fd/{ours}"))
+    }
+  }
+}
+
+/// Expands the parameters inside `$((expr))`, then evaluates it
+fn expand_arith(expr: &str, state: &mut State) -> anyhow::Result<String> {
+  let word =
+    split::word(expr, &state.options).map_err(|_| anyhow!("{expr}: bad arithmetic expansion"))?;
+  Ok(arith::eval(&expand_word(&word, state)?, state)?.to_string())
+}
+
+/// Expands the inside of `${...}`: `#VAR` for the length, `VAR:offset:length` for a substring,
+/// `#`/`%` to strip a matching prefix/suffix (doubled for the longest match) and the `-`, `=`,
+/// `+` and `?` operators, which with a leading `:` treat an empty value as unset, and `/pat/repl`
+/// to replace matches of a pattern.
+fn expand_param(expr: &str, state: &mut State) -> anyhow::Result<String> {
+  let bad_substitution = || anyhow!("${{{expr}}}: bad substitution");
+  if let Some(name) = expr.strip_prefix('#').filter(|name| !name.is_empty()) {
+    let (name, op) = split_name(name);
+    return match split_subscript(op) {
+      _ if name.is_empty() => Err(bad_substitution()),
+      (None, "") => match state.var(name) {
+        None if state.options.nounset => Err(anyhow!("{name}: unbound variable")),
+        value => Ok(value.unwrap_or_default().chars().count().to_string()),
+      },
+      (Some("@" | "*"), "") => Ok(state.array(name).unwrap_or_default().len().to_string()),
+      (Some(subscript), "") => {
+        let element = elements(name, subscript, state)?.concat();
+        Ok(element.chars().count().to_string())
+      }
+      _ => Err(bad_substitution()),
+    };
+  }
+
+  let (name, op) = split_name(expr);
+  if name.is_empty() {
+    return Err(bad_substitution());
+  }
+
+  let (subscript, op) = split_subscript(op);
+  let value = match subscript {
+    Some(_) if !is_name(name) => return Err(bad_substitution()),
+    // Elements that don't exist are unset
+    Some(subscript) => Some(elements(name, subscript, state)?)
+      .filter(|elements| !elements.is_empty())
+      .map(|elements| match subscript {
+        "*" => state.join_with_ifs(&elements),
+        _ => elements.join(" "),
+      }),
+    None => state.var(name),
+  };
+  // `set -u` makes an unset parameter an error, unless the expansion says what to do instead
+  let handled = op.trim_start_matches(':').starts_with(['-', '=', '?', '+']);
+  if value.is_none() && state.options.nounset && !handled {
+    return Err(anyhow!("{name}: unbound variable"));
+  }
+  let (colon, op) = op.strip_prefix(':').map_or((false, op), |op| (true, op));
+  let Some(operator) = op.chars().next() else {
+    return if colon { Err(bad_substitution()) } else { Ok(value.unwrap_or_default()) };
+  };
+  if colon && !['-', '=', '?', '+'].contains(&operator) {
+    return substring(&value.unwrap_or_default(), op);
+  }
+  let unset = value.as_ref().is_none_or(|value| colon && value.is_empty());
+  let word = |state: &mut State| {
+    let word = split::word(&op[1..], &state.options).map_err(|_| bad_substitution())?;
+    expand_word(&word, state)
+  };
+
+  match operator {
+    '-' if unset => word(state),
+    '=' if unset => {
+      if !is_name(name) {
+        return Err(anyhow!("${name}: cannot assign in this way"));
+      }
+      let value = word(state)?;
+      state.set_var(name, value.clone())?;
+      Ok(value)
+    }
+    '?' if unset => {
+      let message = word(state)?;
+      let message = if message.is_empty() { "parameter null or not set" } else { &message };
+      Err(anyhow!("{name}: {message}"))
+    }
+    '#' | '%' if !colon => {
+      let longest = op[1..].starts_with(operator);
+      let pattern =
+        split::word(&op[1 + longest as usize..], &state.options).map_err(|_| bad_substitution())?;
+      let pattern = expand_pattern(&pattern, state)?;
+      Ok(remove_affix(
+        &value.unwrap_or_default(),
+        operator,
+        longest,
+        &pattern,
+        state.options.extglob,
+      ))
+    }
+    '/' if !colon => {
+      let anchor = op[1..].chars().next().filter(|c| ['/', '#', '%'].contains(c));
+      let (pattern, replacement) = split_slash(&op[1 + anchor.map_or(0, char::len_utf8)..]);
+      let pattern = split::word(pattern, &state.options).map_err(|_| bad_substitution())?;
+      let pattern = expand_pattern(&pattern, state)?;
+      let replacement = split::word(replacement.unwrap_or_default(), &state.options)
+        .map_err(|_| bad_substitution())?;
+      let replacement = expand_word(&replacement, state)?;
+      let value = value.unwrap_or_default();
+      Ok(if pattern.is_empty() {
+        value
+      } else {
+        substitute(&value, anchor, &pattern, &replacement, state.options.extglob)
+      })
+    }
+    '+' if unset => Ok(String::new()),
+    '+' => word(state),
+    '-' | '=' | '?' => Ok(value.unwrap_or_default()),
+    _ => Err(bad_substitution()),
+  }
+}
@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+
+use crate::{CommandErr, CommandIn, CommandOut, duplicate};
+
+/// The utilities the shell stands in for when `$PATH` has no program by their name, so that it
+/// stays usable on systems without them. `echo` and `pwd` are builtins, so need no fallback.
+pub const FALLBACKS: [&str; 3] = ["cat", "true", "false"];
+
+/// Runs the stand-in for the utility `name`, returning its exit status
+pub fn run(
+  name: &str,
+  args: &[String],
+  stdin: Option<CommandIn>,
+  stdout: &mut CommandOut,
+  stderr: &mut CommandErr,
+) -> io::Result<i32> {
+  match name {
+    "cat" => cat(args, stdin, stdout, stderr),
+    "true" => Ok(0),
+    _ => Ok(1),
+  }
+}
+
+/// Copies each file, or standard input for `-` or no files, to `stdout`
+fn cat(
+  args: &[String],
+  stdin: Option<CommandIn>,
+  stdout: &mut CommandOut,
+  stderr: &mut CommandErr,
+) -> io::Result<i32> {
+  let stdin = || duplicate(stdin.as_ref().map_or(libc::STDIN_FILENO, AsRawFd::as_raw_fd));
+  let files = if args.is_empty() { &["-".to_owned()][..] } else { args };
+  let mut status = 0;
+  for file in files {
+    let input = match file.as_str() {
+      "-" => stdin(),
+      path => File::open(path),
+    };
+    let res = input.and_then(|mut input| io::copy(&mut input, stdout));
+    match res {
+      Ok(_) => {}
+      // The reader going away ends it all
+      Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(128 + libc::SIGPIPE),
+      Err(e) => {
+        let message = match e.kind() {
+          io::ErrorKind::NotFound => "No such file or directory".to_owned(),
+          io::ErrorKind::PermissionDenied => "Permission denied".to_owned(),
+          io::ErrorKind::IsADirectory => "Is a directory".to_owned(),
+          _ => e.to_string(),
+        };
+        writeln!(stderr, "cat: {file}: {message}")?;
+        status = 1;
+      }
+    }
+  }
+  Ok(status)
+}
@@ -0,0 +1,250 @@
+/// Escapes the characters `matches` treats specially, so `s` only matches itself
+pub fn escape(s: &str) -> String {
+  let mut res = String::with_capacity(s.len());
+  for c in s.chars() {
+    if ['*', '?', '[', ']', '\\', '(', ')', '|'].contains(&c) {
+      res.push('\\');
+    }
+    res.push(c);
+  }
+  res
+}
+
+/// Whether `pattern` has an unescaped `*`, `?` or `[`, or with `extglob` a `+(`, `@(` or `!(`, so
+/// it could match something besides itself
+pub fn has_magic(pattern: &str, extglob: bool) -> bool {
+  let mut chars = pattern.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => _ = chars.next(),
+      '*' | '?' | '[' => return true,
+      '+' | '@' | '!' if extglob && chars.as_str().starts_with('(') => return true,
+      _ => {}
+    }
+  }
+  false
+}
+
+/// Removes the backslashes from a pattern without magic, turning it back into the text it matches
+fn unescape(pattern: &str) -> String {
+  let mut res = String::with_capacity(pattern.len());
+  let mut chars = pattern.chars();
+  while let Some(c) = chars.next() {
+    res.extend(if c == '\\' { chars.next() } else { Some(c) });
+  }
+  res
+}
+
+/// Expands `pattern` into the sorted paths it matches, one `/`-separated component at a time.
+/// Hidden files only match components that start with a `.` themselves.
+pub fn expand_paths(pattern: &str, extglob: bool) -> Vec<String> {
+  let mut paths = vec![if pattern.starts_with('/') { "/".to_owned() } else { String::new() }];
+  let join = |path: &str, name: &str| match path {
+    "" => name.to_owned(),
+    path if path.ends_with('/') => format!("{path}{name}"),
+    path => format!("{path}/{name}"),
+  };
+
+  for component in pattern.split('/').filter(|component| !component.is_empty()) {
+    if !has_magic(component, extglob) {
+      let name = unescape(component);
+      paths = paths.iter().map(|path| join(path, &name)).collect();
+      continue;
+    }
+    let mut matched = vec![];
+    for path in &paths {
+      let Ok(entries) = std::fs::read_dir(if path.is_empty() { "." } else { path }) else {
+        continue;
+      };
+      let names = entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok());
+      let names = names.filter(|name| !name.starts_with('.') || component.starts_with('.'));
+      let names = names.filter(|name| matches(component, name, extglob));
+      matched.extend(names.map(|name| join(path, &name)));
+    }
+    paths = matched;
+  }
+
+  let mut paths = paths
+    .into_iter()
+    .filter(|path| std::fs::symlink_metadata(path).is_ok())
+    .filter(|path| !pattern.ends_with('/') || std::path::Path::new(path).is_dir())
+    .map(|path| if pattern.ends_with('/') { path + "/" } else { path })
+    .collect::<Vec<_>>();
+  paths.sort();
+  paths
+}
+
+/// Matches `text` against a shell pattern made of `*`, `?`, `[...]` and `\`-escaped characters,
+/// along with the groups of `extended` if `extglob` is set
+pub fn matches(pattern: &str, text: &str, extglob: bool) -> bool {
+  let pattern = pattern.chars().collect::<Vec<_>>();
+  let text = text.chars().collect::<Vec<_>>();
+  if extglob {
+    return extended(&pattern, &text);
+  }
+  let (mut p, mut t) = (0, 0);
+  // Where to resume after the last `*` if the rest fails to match
+  let mut backtrack = None;
+
+  while t < text.len() {
+    if pattern.get(p) == Some(&'*') {
+      p += 1;
+      backtrack = Some((p, t));
+      continue;
+    }
+    if let Some((true, len)) = single(&pattern[p..], text[t]) {
+      p += len;
+      t += 1;
+      continue;
+    }
+    match backtrack {
+      Some((bp, bt)) => {
+        p = bp;
+        t = bt + 1;
+        backtrack = Some((bp, bt + 1));
+      }
+      None => return false,
+    }
+  }
+
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `text` against `pattern` recursively, so that besides what `matches` supports it can
+/// handle the `extglob` groups `?(...)`, `*(...)`, `+(...)`, `@(...)` and `!(...)`, which match
+/// zero or one, any number, one or more, exactly one or none of their `|`-separated patterns
+fn extended(pattern: &[char], text: &[char]) -> bool {
+  let group = match pattern {
+    ['?' | '*' | '+' | '@' | '!', '(', rest @ ..] => group_len(rest),
+    _ => None,
+  };
+  if let Some(len) = group {
+    let alternatives = alternatives(&pattern[2..2 + len]);
+    let rest = &pattern[2 + len + 1..];
+    let one = |text: &[char]| alternatives.iter().any(|alternative| extended(alternative, text));
+    return (0..=text.len()).any(|i| {
+      let (head, tail) = text.split_at(i);
+      let matched = match pattern[0] {
+        '?' => head.is_empty() || one(head),
+        '*' => head.is_empty() || repeated(&alternatives, head),
+        '+' => one(head) || repeated(&alternatives, head),
+        '@' => one(head),
+        _ => !one(head),
+      };
+      matched && extended(rest, tail)
+    });
+  }
+
+  match (pattern, text) {
+    ([], _) => text.is_empty(),
+    (['*', rest @ ..], _) => (0..=text.len()).any(|i| extended(rest, &text[i..])),
+    (_, [c, tail @ ..]) => {
+      matches!(single(pattern, *c), Some((true, len)) if extended(&pattern[len..], tail))
+    }
+    (_, []) => false,
+  }
+}
+
+/// Whether `text` is made of one or more nonempty pieces that each match one of `alternatives`
+fn repeated(alternatives: &[&[char]], text: &[char]) -> bool {
+  (1..=text.len()).any(|i| {
+    alternatives.iter().any(|alternative| extended(alternative, &text[..i]))
+      && (i == text.len() || repeated(alternatives, &text[i..]))
+  })
+}
+
+/// The length of the `extglob` group in `pattern`, which starts after its `(`, up to its `)`
+fn group_len(pattern: &[char]) -> Option<usize> {
+  let mut depth = 0;
+  let mut i = 0;
+  while i < pattern.len() {
+    match pattern[i] {
+      '\\' => i += 1,
+      '(' => depth += 1,
+      ')' if depth == 0 => return Some(i),
+      ')' => depth -= 1,
+      _ => {}
+    }
+    i += 1;
+  }
+  None
+}
+
+/// Splits the inside of an `extglob` group at the `|`s that aren't in a nested group
+fn alternatives(group: &[char]) -> Vec<&[char]> {
+  let mut res = vec![];
+  let (mut depth, mut start, mut i) = (0, 0, 0);
+  while i < group.len() {
+    match group[i] {
+      '\\' => i += 1,
+      '(' => depth += 1,
+      ')' => depth -= 1,
+      '|' if depth == 0 => {
+        res.push(&group[start..i]);
+        start = i + 1;
+      }
+      _ => {}
+    }
+    i += 1;
+  }
+  res.push(&group[start..]);
+  res
+}
+
+/// Matches `c` against the first non-`*` element of `pattern`, returning whether it matched and
+/// how many pattern characters the element spans
+fn single(pattern: &[char], c: char) -> Option<(bool, usize)> {
+  match pattern {
+    [] => None,
+    ['?', ..] => Some((true, 1)),
+    ['\\', escaped, ..] => Some((*escaped == c, 2)),
+    ['[', rest @ ..] => bracket(rest, c).or(Some(('[' == c, 1))),
+    [literal, ..] => Some((*literal == c, 1)),
+  }
+}
+
+/// Matches a `[...]` expression, given the pattern after the `[`, or `None` if it's unterminated
+fn bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+  let (negated, mut i) = match pattern.first() {
+    Some('!' | '^') => (true, 1),
+    _ => (false, 0),
+  };
+  let mut matched = false;
+  let mut first = true;
+
+  loop {
+    match pattern.get(i..)? {
+      [']', ..] if !first => return Some((matched != negated, i + 2)),
+      ['[', ':', rest @ ..] => {
+        let end = rest.windows(2).position(|w| w == [':', ']'])?;
+        let class = rest[..end].iter().collect::<String>();
+        matched |= match class.as_str() {
+          "alpha" => c.is_alphabetic(),
+          "digit" => c.is_ascii_digit(),
+          "alnum" => c.is_alphanumeric(),
+          "space" => c.is_whitespace(),
+          "upper" => c.is_uppercase(),
+          "lower" => c.is_lowercase(),
+          "punct" => c.is_ascii_punctuation(),
+          "xdigit" => c.is_ascii_hexdigit(),
+          _ => false,
+        };
+        i += end + 4;
+      }
+      ['\\', escaped, ..] => {
+        matched |= *escaped == c;
+        i += 2;
+      }
+      [lo, '-', hi, ..] if *hi != ']' => {
+        matched |= (*lo..=*hi).contains(&c);
+        i += 3;
+      }
+      [literal, ..] => {
+        matched |= *literal == c;
+        i += 1;
+      }
+      [] => return None,
+    }
+    first = false;
+  }
+}
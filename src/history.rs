@@ -0,0 +1,169 @@
+use std::ffi::CString;
+
+/// The line an event designator after a `!` refers to, e.g. `!` for `!!`, `-2`, `12`, `ls` or
+/// `?needle?`, along with how many characters of `rest` it took
+fn event<'a>(rest: &[char], history: &'a [String]) -> Result<(&'a str, usize), String> {
+  let text = |len: usize| rest[..len].iter().collect::<String>();
+  let not_found = |len: usize| format!("!{}: event not found", text(len));
+  let count = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+  let (line, len) = match rest[0] {
+    // `!$`, `!^` and `!*` are words of the last line
+    '!' | '$' | '^' | '*' => (history.last(), (rest[0] == '!') as usize),
+    '-' => {
+      let count = rest[1..].iter().take_while(|c| c.is_ascii_digit()).count();
+      let n = text(count + 1)[1..].parse::<usize>().map_err(|_| not_found(count + 1))?;
+      (history.len().checked_sub(n).and_then(|i| history.get(i)), count + 1)
+    }
+    _ if count > 0 => {
+      let n = text(count).parse::<usize>().map_err(|_| not_found(count))?;
+      (n.checked_sub(1).and_then(|i| history.get(i)), count)
+    }
+    '?' => {
+      let end = rest[1..].iter().position(|c| *c == '?' || *c == '\n');
+      let needle = text(end.map_or(rest.len(), |end| end + 1))[1..].to_owned();
+      let len = end.map_or(rest.len(), |end| end + 1 + (rest[end + 1] == '?') as usize);
+      (history.iter().rev().find(|line| line.contains(&needle)), len)
+    }
+    _ => {
+      let len = rest.iter().take_while(|c| !c.is_whitespace() && **c != ':').count();
+      let prefix = text(len);
+      (history.iter().rev().find(|line| line.starts_with(&prefix)), len)
+    }
+  };
+  line.map(|line| (line.as_str(), len)).ok_or_else(|| not_found(len.max(1)))
+}
+
+/// Picks the words of `line` a word designator asks for: `^` for the first argument, `$` for the
+/// last, `*` for all the arguments, or `N` for word `N` counting the command as 0
+fn words(line: &str, designator: &str) -> Option<String> {
+  let words = line.split_whitespace().collect::<Vec<_>>();
+  match designator {
+    "^" => words.get(1).map(|word| word.to_string()),
+    "$" => words.last().map(|word| word.to_string()),
+    "*" => Some(words.get(1..).unwrap_or_default().join(" ")),
+    n => words.get(n.parse::<usize>().ok()?).map(|word| word.to_string()),
+  }
+}
+
+/// Replaces history references starting with `!` in a line of interactive input with the lines
+/// from `history` they refer to: `!!` for the last line, `!N` for line `N`, `!-N` for the `N`th
+/// last, `!prefix` and `!?text?` for the last line starting with or containing some text, and
+/// `!$`, `!^` and `!*` for words of the last line. A `:` and a word designator can follow an event
+/// to pick its words. A `!` stays as it is when escaped with `\`, inside single quotes, or when
+/// followed by a blank, `=` or `(`.
+pub fn expand(line: &str, history: &[String]) -> Result<String, String> {
+  let chars = line.chars().collect::<Vec<_>>();
+  let mut res = String::new();
+  let mut quote = None;
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    match (quote, c) {
+      (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+      (None, '\'' | '"') => quote = Some(c),
+      (Some('\''), _) => {}
+      (_, '\\') if i + 1 < chars.len() => {
+        res.push(c);
+        res.push(chars[i + 1]);
+        i += 2;
+        continue;
+      }
+      // `$!` is the parameter, not history
+      (_, '!') if i > 0 && chars[i - 1] == '$' => {}
+      (_, '!') if i + 1 < chars.len() && !" \t\n=(".contains(chars[i + 1]) => {
+        let rest = &chars[i + 1..];
+        if quote == Some('"') && rest[0] == '"' {
+          res.push(c);
+          i += 1;
+          continue;
+        }
+        let (line, len) = event(rest, history)?;
+        let reference = || format!("!{}", rest[..len].iter().collect::<String>());
+        let designator = match rest[0] {
+          '$' | '^' | '*' => Some(rest[0].to_string()),
+          _ => None,
+        };
+        // An explicit `:designator` after the event
+        let after = &rest[len..];
+        let (designator, len) = match (designator, after.first()) {
+          (Some(designator), _) => (Some(designator), 1),
+          (None, Some(':')) => {
+            let end =
+              after[1..].iter().position(|c| c.is_whitespace()).map_or(after.len(), |end| end + 1);
+            (Some(after[1..end].iter().collect::<String>()), len + end)
+          }
+          _ => (None, len),
+        };
+        match designator {
+          Some(designator) => res.push_str(
+            &words(line, &designator)
+              .ok_or_else(|| format!("{}: bad word specifier", reference()))?,
+          ),
+          None => res.push_str(line),
+        }
+        i += 1 + len;
+        continue;
+      }
+      _ => {}
+    }
+    res.push(c);
+    i += 1;
+  }
+  Ok(res)
+}
+
+/// Parses the contents of a history file into its entries and their times, which are given by
+/// `#<epoch seconds>` comment lines before them. Entries saved as `history` lists them, with their
+/// number in front, lose the number.
+pub fn load(content: &str) -> (Vec<String>, Vec<Option<i64>>) {
+  let (mut lines, mut times) = (vec![], vec![]);
+  let mut time = None;
+  for line in content.lines() {
+    match line.strip_prefix('#').and_then(|epoch| epoch.parse().ok()) {
+      Some(epoch) => time = Some(epoch),
+      None => {
+        lines.push(strip_number(line).to_owned());
+        times.push(time.take());
+      }
+    }
+  }
+  (lines, times)
+}
+
+/// `line` without the `    N  ` that `history` puts before an entry
+fn strip_number(line: &str) -> &str {
+  let number = line.trim_start_matches(' ');
+  let command = number.trim_start_matches(|c: char| c.is_ascii_digit());
+  match command.strip_prefix("  ") {
+    Some(command) if number.len() < line.len() && command.len() + 2 < number.len() => command,
+    _ => line,
+  }
+}
+
+/// Formats history entries for a history file, preceding each with a `#<epoch seconds>` line if
+/// `timestamps` is set and its time is known
+pub fn save(lines: &[String], times: &[Option<i64>], timestamps: bool) -> String {
+  let mut content = String::new();
+  for (line, time) in lines.iter().zip(times) {
+    if let Some(time) = time.filter(|_| timestamps) {
+      content.push_str(&format!("#{time}\n"));
+    }
+    content.push_str(line);
+    content.push('\n');
+  }
+  content
+}
+
+/// Renders `time` in the local time zone with the `strftime` format of `$HISTTIMEFORMAT`
+pub fn format_time(format: &str, time: i64) -> String {
+  let Ok(format) = CString::new(format) else {
+    return String::new();
+  };
+  let mut buf = [0u8; 256];
+  let len = unsafe {
+    let mut tm = std::mem::zeroed();
+    libc::localtime_r(&time, &mut tm);
+    libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm)
+  };
+  String::from_utf8_lossy(&buf[..len]).into_owned()
+}
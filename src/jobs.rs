@@ -0,0 +1,153 @@
+use std::fmt::Display;
+
+/// Turns a status from `waitpid` into an exit status, which is 128 plus the signal for a child
+/// killed by one, e.g. 130 for `SIGINT`
+pub fn exit_status(status: i32) -> i32 {
+  if libc::WIFSIGNALED(status) { 128 + libc::WTERMSIG(status) } else { libc::WEXITSTATUS(status) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+  Running,
+  Done(i32),
+}
+
+impl Display for JobStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      JobStatus::Running => f.write_str("Running"),
+      JobStatus::Done(0) => f.write_str("Done"),
+      JobStatus::Done(status) => write!(f, "Exit {status}"),
+    }
+  }
+}
+
+/// A command started with `&`
+#[derive(Clone, Debug)]
+pub struct Job {
+  /// The `N` of `%N`
+  pub id: usize,
+  pub pid: libc::pid_t,
+  /// The command as it was typed
+  pub command: String,
+  pub status: JobStatus,
+  /// Set by `disown -h`: the job stays listed but isn't sent `SIGHUP` when the shell exits
+  pub no_hangup: bool,
+}
+
+/// The background jobs, oldest first, so the last is the current job `%+` and the one before it
+/// the previous job `%-`, along with the processes of jobs that were disowned, which are still the
+/// shell's to collect
+#[derive(Debug, Default)]
+pub struct Jobs(pub Vec<Job>, pub Vec<libc::pid_t>);
+
+impl Jobs {
+  /// Adds a job for `pid`, returning its number
+  pub fn add(&mut self, pid: libc::pid_t, command: String) -> usize {
+    let id = self.0.last().map_or(1, |job| job.id + 1);
+    self.0.push(Job {
+      id,
+      pid,
+      command,
+      status: JobStatus::Running,
+      no_hangup: false,
+    });
+    id
+  }
+
+  /// Collects the status of jobs that have finished, without blocking
+  pub fn update(&mut self) {
+    for job in self.0.iter_mut().filter(|job| job.status == JobStatus::Running) {
+      let mut status = 0;
+      if unsafe { libc::waitpid(job.pid, &mut status, libc::WNOHANG) } == job.pid {
+        job.status = JobStatus::Done(exit_status(status));
+      }
+    }
+    // Nobody wants to know how disowned jobs went, but they're gone once collected
+    self.1.retain(|&pid| unsafe { libc::waitpid(pid, &mut 0, libc::WNOHANG) } == 0);
+  }
+
+  /// Waits for one of the jobs at `indices` to finish, one that already has counting first,
+  /// returning its index, or `None` if a signal interrupted the wait
+  pub fn wait_any(&mut self, indices: &[usize]) -> Option<usize> {
+    self.update();
+    loop {
+      let done = indices.iter().find(|&&i| self.0[i].status != JobStatus::Running);
+      if let Some(&index) = done {
+        return Some(index);
+      }
+      // Any child may be the next to finish, including jobs that aren't being waited for, which
+      // are kept as done for later
+      let mut status = 0;
+      let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+      if pid < 0 {
+        return None;
+      }
+      if let Some(job) = self.0.iter_mut().find(|job| job.pid == pid) {
+        job.status = JobStatus::Done(exit_status(status));
+      }
+    }
+  }
+
+  /// Takes the jobs that have finished out of the list, along with the marks they had, so they
+  /// can be reported once
+  pub fn take_done(&mut self) -> Vec<(char, Job)> {
+    let marks = (0..self.0.len()).map(|i| self.mark(i)).collect::<Vec<_>>();
+    let marked = marks.into_iter().zip(self.0.drain(..));
+    let (done, running): (Vec<_>, Vec<_>) =
+      marked.partition(|(_, job)| job.status != JobStatus::Running);
+    self.0 = running.into_iter().map(|(_, job)| job).collect();
+    done
+  }
+
+  /// Finds the job `spec` refers to, either `%N`, `%%`, `%+`, `%-`, `%string` for the job whose
+  /// command starts with `string`, `%?string` for the one whose command contains it, or a process
+  /// ID, returning its index. A string that fits more than one job finds none.
+  pub fn find(&self, spec: &str) -> Option<usize> {
+    match spec {
+      "%%" | "%+" => self.0.len().checked_sub(1),
+      "%-" => self.0.len().checked_sub(2),
+      _ => match spec.strip_prefix('%') {
+        Some(text) if let Ok(id) = text.parse::<usize>() => {
+          self.0.iter().position(|job| job.id == id)
+        }
+        Some(text) => {
+          let fits = |job: &Job| match text.strip_prefix('?') {
+            Some(text) => job.command.contains(text),
+            None => job.command.starts_with(text),
+          };
+          let mut found = self.0.iter().enumerate().filter(|(_, job)| fits(job));
+          match (found.next(), found.next()) {
+            (Some((index, _)), None) => Some(index),
+            _ => None,
+          }
+        }
+        None => {
+          let pid = spec.parse::<libc::pid_t>().ok()?;
+          self.0.iter().position(|job| job.pid == pid)
+        }
+      },
+    }
+  }
+
+  /// The `+`, `-` or space `jobs` marks the job at `index` with
+  pub fn mark(&self, index: usize) -> char {
+    match self.0.len() - index {
+      1 => '+',
+      2 => '-',
+      _ => ' ',
+    }
+  }
+
+  /// Sends `SIGHUP` to the jobs still running, except those marked by `disown -h`, as the shell
+  /// exits
+  pub fn hang_up(&self) {
+    let running = self.0.iter().filter(|job| job.status == JobStatus::Running);
+    for job in running.filter(|job| !job.no_hangup) {
+      unsafe {
+        libc::kill(job.pid, libc::SIGHUP);
+        libc::kill(job.pid, libc::SIGCONT);
+      }
+    }
+  }
+}
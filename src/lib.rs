@@ -0,0 +1,1972 @@
+use std::{
+  cell::Cell,
+  cmp::Ordering,
+  collections::{BTreeMap, HashSet},
+  ffi::OsString,
+  fs::{File, OpenOptions},
+  io::{self, PipeReader, PipeWriter, Read, Stderr, Stdout, Write},
+  iter::once,
+  ops::Range,
+  os::{
+    fd::{AsRawFd, BorrowedFd, RawFd},
+    unix::{ffi::OsStringExt, fs::PermissionsExt, process::CommandExt},
+  },
+  path::{Path, PathBuf},
+  process::Stdio,
+  rc::Rc,
+  time::{Duration, Instant},
+};
+
+mod split;
+use anyhow::{Context, anyhow};
+use split::*;
+mod builtin;
+use builtin::*;
+mod expand;
+mod fallback;
+use expand::*;
+mod arith;
+mod color;
+mod edit;
+mod glob;
+mod history;
+mod jobs;
+mod options;
+mod parse;
+use parse::{AndOr, Compound, Connector, List, Node, Pipeline, Redirect, SimpleCommand};
+mod signal;
+
+/// The directories of `$PATH`, where an empty entry means the current directory
+fn path_dirs(path: &str) -> Vec<PathBuf> {
+  let dirs = std::env::split_paths(path);
+  dirs.map(|dir| if dir.as_os_str().is_empty() { ".".into() } else { dir }).collect()
+}
+
+/// Whether `path` is a file someone may execute
+fn is_executable(path: &Path) -> bool {
+  path
+    .metadata()
+    .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Finds the executable `command` in the first of `paths` that has it, skipping directories that
+/// can't be read. An entry can also be an executable itself.
+fn search(paths: &Vec<PathBuf>, command: &str) -> Option<PathBuf> {
+  for path in paths {
+    let candidate = if path.is_file() { path.clone() } else { path.join(command) };
+    if is_executable(&candidate) && candidate.file_name().is_some_and(|name| name == command) {
+      return Some(candidate);
+    }
+  }
+  None
+}
+
+/// Remembers where commands were found on `$PATH`, forgetting everything when `$PATH` changes
+#[derive(Default)]
+pub(crate) struct PathIndex {
+  path: String,
+  /// Command name to its location and how many times it was looked up
+  pub commands: BTreeMap<String, (PathBuf, usize)>,
+}
+
+impl PathIndex {
+  pub fn lookup(&mut self, command: &str, path: &str) -> Option<PathBuf> {
+    if self.path != path {
+      self.path = path.to_owned();
+      self.commands.clear();
+    }
+    if let Some((location, hits)) = self.commands.get_mut(command) {
+      *hits += 1;
+      return Some(location.clone());
+    }
+    let location = search(&path_dirs(path), command)?;
+    self.commands.insert(command.to_owned(), (location.clone(), 1));
+    Some(location)
+  }
+
+  /// Where `command` was found on `path` before, without looking for it
+  pub fn hashed(&self, command: &str, path: &str) -> Option<&PathBuf> {
+    let (location, _) = self.commands.get(command).filter(|_| self.path == path)?;
+    Some(location)
+  }
+}
+
+/// The names of the executables in `paths`, skipping directories that can't be read
+fn executables(paths: &Vec<PathBuf>) -> Vec<String> {
+  let mut res = vec![];
+  for path in paths {
+    if path.is_file() {
+      if is_executable(path) {
+        res.extend(path.file_name().and_then(|name| name.to_str()).map(str::to_owned));
+      }
+    } else if let Ok(entries) = std::fs::read_dir(path) {
+      for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        if is_executable(&path) {
+          res.extend(path.file_name().and_then(|name| name.to_str()).map(str::to_owned));
+        }
+      }
+    }
+  }
+
+  res
+}
+
+/// The executables on `$PATH` that commands complete to, listed again when `$PATH` changes
+#[derive(Default)]
+struct Executables {
+  /// The `$PATH` they were listed from, if they have been
+  path: Option<String>,
+  names: Vec<String>,
+}
+
+impl Executables {
+  fn get(&mut self, path: &str) -> &[String] {
+    if self.path.as_deref() != Some(path) {
+      self.path = Some(path.to_owned());
+      self.names = executables(&path_dirs(path));
+    }
+    &self.names
+  }
+}
+
+#[derive(Debug)]
+enum CommandKind {
+  Function(Rc<Node>),
+  Builtin(Builtin),
+  Program(PathBuf),
+  NotFound(String),
+}
+
+/// `$PATH` guaranteed to find the standard utilities, for `command -p`
+pub(crate) const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+impl CommandKind {
+  /// Resolves `command` on `path`, bypassing the `PathIndex`
+  fn parse_on_path(command: &str, path: &str) -> Self {
+    let command = command.trim();
+    if let Ok(builtin) = command.parse() {
+      CommandKind::Builtin(builtin)
+    } else if let Some(program) = search(&path_dirs(path), command) {
+      CommandKind::Program(program)
+    } else {
+      CommandKind::NotFound(command.to_owned())
+    }
+  }
+
+  fn parse(command: &str, state: &mut State) -> Self {
+    Self::resolve(command, state, true)
+  }
+
+  /// Resolves `command` to a builtin or program, as `command` and `builtin` do
+  fn parse_skipping_functions(command: &str, state: &mut State) -> Self {
+    Self::resolve(command, state, false)
+  }
+
+  fn resolve(command: &str, state: &mut State, functions: bool) -> Self {
+    let command = command.trim();
+    match state.resolve_command(command, functions) {
+      Resolution::Function(body) => CommandKind::Function(body),
+      Resolution::Builtin(builtin) => CommandKind::Builtin(builtin),
+      Resolution::File(path) => CommandKind::Program(path),
+      _ => CommandKind::NotFound(command.to_owned()),
+    }
+  }
+}
+
+/// How deep functions can call each other when `$FUNCNEST` doesn't say
+const MAX_FUNCTION_DEPTH: usize = 1000;
+
+pub(crate) enum ControlFlow {
+  Repl,
+  Exit,
+  /// `break n`, leaving the innermost `n` loops
+  Break(usize),
+  /// `continue n`, leaving the innermost `n - 1` loops and going on with the next
+  Continue(usize),
+}
+
+#[derive(Debug)]
+struct Command {
+  kind: CommandKind,
+  args: Vec<String>,
+  /// `NAME=value` prefixes, only visible to this command
+  assignments: Vec<(String, String)>,
+}
+
+impl Command {
+  fn run(
+    self,
+    state: &mut State,
+    stdout: CommandOut,
+    mut stderr: CommandErr,
+    stdin: Option<CommandIn>,
+  ) -> anyhow::Result<Option<libc::pid_t>> {
+    match self.kind {
+      CommandKind::Function(body) => {
+        // `$FUNCNEST` limits how deep functions can call each other, so runaway recursion is an
+        // error rather than the end of the shell
+        let limit = state.var("FUNCNEST").and_then(|limit| limit.parse().ok()).filter(|&n| n > 0);
+        let limit = limit.unwrap_or(MAX_FUNCTION_DEPTH);
+        if state.function_depth >= limit {
+          eprintln!("maximum function nesting level exceeded ({limit})");
+          return Err(Status(1).into());
+        }
+        let positional = std::mem::replace(&mut state.positional, self.args);
+        state.function_depth += 1;
+        with_fds(stdin, stdout, stderr, || run_node(state, &body, None, None));
+        state.function_depth -= 1;
+        state.positional = positional;
+        Ok(None)
+      }
+      CommandKind::Builtin(Builtin::Builtin) => {
+        let mut args = self.args;
+        if args.is_empty() {
+          state.status = 0;
+          return Ok(None);
+        }
+        let name = args.remove(0);
+        let Ok(builtin) = name.parse() else {
+          writeln!(stderr, "builtin: {name}: not a shell builtin")?;
+          return Err(Status(1).into());
+        };
+        let command = Command {
+          kind: CommandKind::Builtin(builtin),
+          args,
+          assignments: self.assignments,
+        };
+        command.run(state, stdout, stderr, stdin)
+      }
+      // `command name` resolves `name` itself, skipping aliases
+      CommandKind::Builtin(Builtin::Command)
+        if !self.args.first().is_some_and(|arg| arg == "-v" || arg == "-V") =>
+      {
+        let mut args = self.args;
+        let default_path = args.first().is_some_and(|arg| arg == "-p");
+        if default_path {
+          args.remove(0);
+        }
+        if args.is_empty() {
+          return Ok(None);
+        }
+        let name = args.remove(0);
+        let command = Command {
+          kind: if default_path {
+            CommandKind::parse_on_path(&name, DEFAULT_PATH)
+          } else {
+            CommandKind::parse_skipping_functions(&name, state)
+          },
+          args,
+          assignments: self.assignments,
+        };
+        command.run(state, stdout, stderr, stdin)
+      }
+      CommandKind::Builtin(builtin) => {
+        let mut saved = vec![];
+        let mut res = Ok(());
+        for (name, value) in self.assignments {
+          let old = state.vars.get(&name).map(|var| var.value.clone());
+          if let Err(e) = state.set_var(&name, value) {
+            writeln!(stderr, "{e}")?;
+            res = Err(Status(1).into());
+            break;
+          }
+          saved.push((name, old));
+        }
+        if res.is_ok() {
+          res = builtin.run(state, stdout, stderr, stdin, self.args);
+        }
+        // Put the old values back as they were, without evaluating them again
+        for (name, old) in saved.into_iter().rev() {
+          match old {
+            Some(value) => state.vars.entry(name).or_default().value = value,
+            None => _ = state.vars.remove(&name),
+          }
+        }
+        res?;
+        state.status = 0;
+        Ok(None)
+      }
+      CommandKind::Program(path) => {
+        let command = path.file_name().unwrap(); // guaranteed to exist by parsing
+        let mut cmd = std::process::Command::new(&path);
+        // Bytes that aren't UTF-8 reach the program as they were written
+        let raw = |text: &str| OsString::from_vec(raw_bytes(text));
+        cmd.arg0(command).args(self.args.iter().map(|arg| raw(arg)));
+        cmd.env_clear();
+        let assignments =
+          self.assignments.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        for (name, value) in state.exported().chain(assignments) {
+          cmd.env(name, raw(value));
+        }
+        cmd.stdout(stdout);
+        cmd.stderr(stderr);
+        if let Some(stdin) = stdin {
+          cmd.stdin(stdin);
+        }
+        let child = cmd.spawn().context("could not spawn child command")?;
+        Ok(Some(child.id() as libc::pid_t))
+      }
+      CommandKind::NotFound(name) if fallback::FALLBACKS.contains(&name.as_str()) => {
+        let mut stdout = stdout;
+        match fallback::run(&name, &self.args, stdin, &mut stdout, &mut stderr)? {
+          0 => {
+            state.status = 0;
+            Ok(None)
+          }
+          status => Err(Status(status).into()),
+        }
+      }
+      CommandKind::NotFound(name) => {
+        writeln!(stderr, "{name}: command not found")?;
+        stderr.flush()?;
+        Err(Status(127).into())
+      }
+    }
+  }
+}
+
+#[derive(Debug)]
+enum CommandIn {
+  File(File),
+  Pipe(PipeReader),
+}
+
+impl AsRawFd for CommandIn {
+  fn as_raw_fd(&self) -> RawFd {
+    match self {
+      CommandIn::File(file) => file.as_raw_fd(),
+      CommandIn::Pipe(pipe) => pipe.as_raw_fd(),
+    }
+  }
+}
+
+impl From<CommandIn> for Stdio {
+  fn from(inn: CommandIn) -> Self {
+    match inn {
+      CommandIn::File(file) => file.into(),
+      CommandIn::Pipe(pipe) => pipe.into(),
+    }
+  }
+}
+
+#[derive(Debug)]
+enum CommandOut {
+  File(File),
+  Pipe(PipeWriter),
+  Stdout(Stdout),
+}
+
+impl AsRawFd for CommandOut {
+  fn as_raw_fd(&self) -> RawFd {
+    match self {
+      CommandOut::File(file) => file.as_raw_fd(),
+      CommandOut::Pipe(pipe) => pipe.as_raw_fd(),
+      CommandOut::Stdout(out) => out.as_raw_fd(),
+    }
+  }
+}
+
+impl CommandOut {
+  /// Whether the output goes to a terminal, for builtins to format it for a person to read
+  fn is_terminal(&self) -> bool {
+    unsafe { libc::isatty(self.as_raw_fd()) == 1 }
+  }
+}
+
+impl From<CommandOut> for Stdio {
+  fn from(out: CommandOut) -> Self {
+    match out {
+      CommandOut::File(file) => file.into(),
+      CommandOut::Pipe(pipe) => pipe.into(),
+      CommandOut::Stdout(out) => out.into(),
+    }
+  }
+}
+
+impl Write for CommandOut {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      CommandOut::File(file) => file.write(buf),
+      CommandOut::Pipe(pipe) => pipe.write(buf),
+      CommandOut::Stdout(out) => out.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      CommandOut::File(file) => file.flush(),
+      CommandOut::Pipe(pipe) => pipe.flush(),
+      CommandOut::Stdout(out) => out.flush(),
+    }
+  }
+}
+
+#[derive(Debug)]
+enum CommandErr {
+  File(File),
+  #[allow(unused)]
+  Pipe(PipeWriter),
+  Stderr(Stderr),
+}
+
+impl AsRawFd for CommandErr {
+  fn as_raw_fd(&self) -> RawFd {
+    match self {
+      CommandErr::File(file) => file.as_raw_fd(),
+      CommandErr::Pipe(pipe) => pipe.as_raw_fd(),
+      CommandErr::Stderr(err) => err.as_raw_fd(),
+    }
+  }
+}
+
+impl CommandErr {
+  /// Whether errors go to a terminal, for builtins to format them for a person to read
+  fn is_terminal(&self) -> bool {
+    unsafe { libc::isatty(self.as_raw_fd()) == 1 }
+  }
+}
+
+impl From<CommandErr> for Stdio {
+  fn from(err: CommandErr) -> Self {
+    match err {
+      CommandErr::File(file) => file.into(),
+      CommandErr::Pipe(pipe) => pipe.into(),
+      CommandErr::Stderr(err) => err.into(),
+    }
+  }
+}
+
+impl Write for CommandErr {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      CommandErr::File(file) => file.write(buf),
+      CommandErr::Pipe(pipe) => pipe.write(buf),
+      CommandErr::Stderr(err) => err.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      CommandErr::File(file) => file.flush(),
+      CommandErr::Pipe(pipe) => pipe.flush(),
+      CommandErr::Stderr(err) => err.flush(),
+    }
+  }
+}
+
+/// Terminal attributes of the controlling tty, saved so raw mode can be left around
+/// programs that take over the process (e.g. `exec`) and on shutdown.
+#[derive(Clone, Copy)]
+pub(crate) struct Terminal {
+  fd: RawFd,
+  /// Whether the attributes could be read, without which there's nothing to set or restore
+  valid: bool,
+  original: libc::termios,
+  raw: libc::termios,
+}
+
+impl Terminal {
+  fn new(fd: RawFd) -> Self {
+    let mut original = unsafe { std::mem::zeroed() };
+    let valid = unsafe { libc::tcgetattr(fd, &mut original) } == 0;
+    let mut raw = original;
+    raw.c_lflag &= !(libc::ECHO | libc::ICANON);
+    Self {
+      fd,
+      valid,
+      original,
+      raw,
+    }
+  }
+
+  /// Switches to reading a key at a time without echo, returning whether the terminal took it
+  pub fn set_raw(&self) -> bool {
+    self.valid && unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.raw) } == 0
+  }
+
+  pub fn restore(&self) {
+    if self.valid {
+      unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.original) };
+    }
+  }
+}
+
+#[derive(Debug)]
+enum Key {
+  Char(char),
+  Backspace,
+  Tab,
+  Newline,
+  Delete,
+  LeftArrow,
+  RightArrow,
+  Home,
+  End,
+  UpArrow,
+  DownArrow,
+  CtrlL,
+  CtrlD,
+  /// Kill the line before the cursor
+  CtrlU,
+  /// Kill the line from the cursor on
+  CtrlK,
+  /// Kill the word before the cursor
+  CtrlW,
+  /// Put back the last kill
+  CtrlY,
+  /// Swap the text just put back for the kill before it
+  MetaY,
+  /// Swap the characters around the cursor
+  CtrlT,
+  /// Move back to the start of a word
+  MetaB,
+  /// Move on to the end of a word
+  MetaF,
+  /// Kill up to the end of a word
+  MetaD,
+  /// Kill back to the start of a word
+  MetaBackspace,
+  /// Take back the last change to the line
+  Undo,
+  /// Ctrl-X Ctrl-E, edit the line in `$VISUAL` or `$EDITOR` and run it
+  EditCommand,
+  /// A sequence nothing is bound to
+  Unbound,
+  /// The read was interrupted by a trapped signal
+  Interrupt,
+}
+
+impl Key {
+  fn read_key(mut stdin: &io::Stdin) -> Self {
+    let mut byte = [0u8; 1];
+    use Key::*;
+    match stdin.read(&mut byte) {
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => return Interrupt,
+      // A terminal that can't be read any more, as when it hangs up, ends the input too
+      Ok(0) | Err(_) => return CtrlD,
+      Ok(_) => {}
+    }
+    match byte[0] {
+      0x08 | 0x7F => Backspace,
+      0x0C => CtrlL,
+      0x04 => CtrlD,
+      0x15 => CtrlU,
+      0x0B => CtrlK,
+      0x17 => CtrlW,
+      0x19 => CtrlY,
+      0x14 => CtrlT,
+      0x1F => Undo,
+      0x18 => match stdin.read_exact(&mut byte).map(|_| byte[0]) {
+        Ok(0x05) => EditCommand,
+        _ => Unbound,
+      },
+      // A sequence cut short by the end of the input or an error is no key
+      0x1B => Self::read_escape(stdin).unwrap_or(Unbound),
+      b'\t' => Tab,
+      b'\n' | b'\r' => Newline,
+      ch => Char(ch as char),
+    }
+  }
+
+  /// Reads the rest of a sequence that started with an escape
+  fn read_escape(mut stdin: &io::Stdin) -> io::Result<Self> {
+    let mut byte = [0u8; 1];
+    use Key::*;
+    stdin.read_exact(&mut byte)?;
+    Ok(if byte[0] == b'[' {
+      // A control sequence is numbers separated by `;`, then a final letter or `~`, all of
+      // which has to be read so none of it ends up in the line
+      let mut params = String::new();
+      stdin.read_exact(&mut byte)?;
+      while byte[0].is_ascii_digit() || byte[0] == b';' {
+        params.push(byte[0] as char);
+        stdin.read_exact(&mut byte)?;
+      }
+      // Modifiers like the `1;5` of Ctrl+Right come after the key's number
+      let number = params.split(';').next().unwrap_or_default();
+      match (number, byte[0]) {
+        (_, b'A') => UpArrow,
+        (_, b'B') => DownArrow,
+        (_, b'C') => RightArrow,
+        (_, b'D') => LeftArrow,
+        (_, b'H') | ("1" | "7", b'~') => Home,
+        (_, b'F') | ("4" | "8", b'~') => End,
+        ("3", b'~') => Delete,
+        _ => Unbound,
+      }
+    } else if byte[0] == b'O' {
+      // Terminals in application cursor key mode send `ESC O` rather than `ESC [`
+      stdin.read_exact(&mut byte)?;
+      match byte[0] {
+        b'A' => UpArrow,
+        b'B' => DownArrow,
+        b'C' => RightArrow,
+        b'D' => LeftArrow,
+        b'H' => Home,
+        b'F' => End,
+        _ => Unbound,
+      }
+    } else {
+      match byte[0] {
+        b'y' => MetaY,
+        b'b' => MetaB,
+        b'f' => MetaF,
+        b'd' => MetaD,
+        0x08 | 0x7F => MetaBackspace,
+        _ => Unbound,
+      }
+    })
+  }
+}
+
+fn handle_input(state: &mut State, stdin: io::Stdin, executables: &mut Executables) -> String {
+  let mut input = Vec::new();
+  let mut cursor_position: usize = 0;
+  // Owned, as running an editor on the line needs the state
+  let history = state.history.iter().cloned().chain(once(String::new())).collect::<Vec<_>>();
+  let mut hist_pos = history.len() as isize - 1;
+  // Whether the previous key was also a Tab, which lists the completions
+  let mut after_tab = false;
+  let screen = Screen::new(state);
+  // Where the text Ctrl-Y last put back is, while Meta-Y can still swap it
+  let mut yanked: Option<Range<usize>> = None;
+  // The line and cursor before each change to it, for Ctrl-_ to go back to. A run of typed
+  // characters is one change, so a long line pasted in isn't copied again for every character.
+  let mut undo: Vec<(Vec<char>, usize)> = vec![];
+  let mut typing = false;
+
+  loop {
+    let key = Key::read_key(&stdin);
+    let repeated_tab = after_tab;
+    after_tab = matches!(key, Key::Tab);
+    let last_yank = yanked.take();
+    let typed = matches!(key, Key::Char(_));
+    let before = match typing && typed {
+      true => None,
+      false => Some((input.clone(), cursor_position)),
+    };
+    typing = typed;
+
+    use Key::*;
+    match key {
+      Undo => match undo.pop() {
+        Some((line, position)) => {
+          input = line;
+          print!("{}", screen.redraw(&input, cursor_position, 0, position));
+          cursor_position = position;
+          std::io::stdout().flush().unwrap();
+        }
+        None => bell(state),
+      },
+      Char(ch) => {
+        insert_text(&screen, &mut input, &mut cursor_position, ch.encode_utf8(&mut [0; 4]))
+      }
+      RightArrow => {
+        let moved = (cursor_position + 1).min(input.len());
+        print!("{}", screen.reveal(&input, cursor_position, moved));
+        cursor_position = moved;
+        std::io::stdout().flush().unwrap();
+      }
+      Home | End => {
+        let moved = if let Home = key { 0 } else { input.len() };
+        print!("{}", screen.reveal(&input, cursor_position, moved));
+        cursor_position = moved;
+        std::io::stdout().flush().unwrap();
+      }
+      LeftArrow => {
+        let moved = cursor_position.saturating_sub(1);
+        print!("{}", screen.move_cursor(cursor_position, moved));
+        cursor_position = moved;
+        std::io::stdout().flush().unwrap();
+      }
+      Backspace => {
+        if 0 < cursor_position && cursor_position <= input.len() {
+          input.remove(cursor_position - 1);
+          print!(
+            "{}",
+            screen.redraw(&input, cursor_position, cursor_position - 1, cursor_position - 1)
+          );
+          cursor_position -= 1;
+          std::io::stdout().flush().unwrap();
+        }
+      }
+      Delete => {
+        if cursor_position < input.len() {
+          input.remove(cursor_position);
+          print!("{}", screen.redraw(&input, cursor_position, cursor_position, cursor_position));
+          std::io::stdout().flush().unwrap();
+        }
+      }
+      CtrlU => {
+        let range = 0..cursor_position;
+        let killed = delete_range(&screen, &mut input, &mut cursor_position, range);
+        state.kill_ring.push(killed);
+      }
+      CtrlK => {
+        let range = cursor_position..input.len();
+        let killed = delete_range(&screen, &mut input, &mut cursor_position, range);
+        state.kill_ring.push(killed);
+      }
+      CtrlW => {
+        let before = &input[..cursor_position];
+        let end = before.iter().rposition(|c| !c.is_whitespace()).map_or(0, |i| i + 1);
+        let start = before[..end].iter().rposition(|c| c.is_whitespace()).map_or(0, |i| i + 1);
+        let range = start..cursor_position;
+        let killed = delete_range(&screen, &mut input, &mut cursor_position, range);
+        state.kill_ring.push(killed);
+      }
+      CtrlY => {
+        if let Some(kill) = state.kill_ring.yank() {
+          let start = cursor_position;
+          insert_text(&screen, &mut input, &mut cursor_position, kill);
+          yanked = Some(start..cursor_position);
+        }
+      }
+      MetaY => {
+        if let Some(span) = last_yank
+          && let Some(kill) = state.kill_ring.rotate()
+        {
+          let kill = kill.to_owned();
+          cursor_position = span.end;
+          delete_range(&screen, &mut input, &mut cursor_position, span.clone());
+          insert_text(&screen, &mut input, &mut cursor_position, &kill);
+          yanked = Some(span.start..cursor_position);
+        }
+      }
+      MetaB | MetaF => {
+        let moved = match key {
+          MetaB => edit::word_start(&input, cursor_position),
+          _ => edit::word_end(&input, cursor_position),
+        };
+        print!("{}", screen.reveal(&input, cursor_position, moved));
+        cursor_position = moved;
+        std::io::stdout().flush().unwrap();
+      }
+      MetaD | MetaBackspace => {
+        let range = match key {
+          MetaD => cursor_position..edit::word_end(&input, cursor_position),
+          _ => edit::word_start(&input, cursor_position)..cursor_position,
+        };
+        let killed = delete_range(&screen, &mut input, &mut cursor_position, range);
+        state.kill_ring.push(killed);
+      }
+      EditCommand => {
+        print!("{}", screen.reveal(&input, cursor_position, input.len()));
+        println!();
+        let editor = state.var("VISUAL").or_else(|| state.var("EDITOR"));
+        let editor = editor.filter(|editor| !editor.is_empty()).unwrap_or_else(|| "vi".to_owned());
+        let line = String::from_iter(&input);
+        match edit_text(state, &editor, "edit-command", &line) {
+          // The edited command is shown after the prompt and run as if typed
+          Ok(edited) => {
+            println!("{}{edited}", displayed(&prompt(state)));
+            input = edited.chars().collect();
+            break;
+          }
+          // The line is left as it was if the editor failed
+          Err(_) => {
+            print!("{}{}", displayed(&prompt(state)), screen.redraw(&input, 0, 0, cursor_position));
+            std::io::stdout().flush().unwrap();
+          }
+        }
+      }
+      Unbound => bell(state),
+      CtrlT => match edit::transpose(&mut input, cursor_position) {
+        Some(start) => {
+          print!("{}", screen.redraw(&input, cursor_position, start, start + 2));
+          cursor_position = start + 2;
+          std::io::stdout().flush().unwrap();
+        }
+        None => bell(state),
+      },
+      Newline => {
+        // Leave the cursor below all of the line, however many rows it wrapped onto
+        print!("{}", screen.reveal(&input, cursor_position, input.len()));
+        println!();
+        std::io::stdout().flush().unwrap();
+        break;
+      }
+      Tab => {
+        let input_str: String = input[..cursor_position].iter().collect();
+        let word_start = input_str.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &input_str[word_start..];
+        // What goes after a sole completion: a space to start the next word, the `}` of `${`, or
+        // a `/` to carry on into a directory
+        let mut terminator = ' ';
+        let mut paths = false;
+        // The text being completed and the words it could become
+        let (partial, candidates) =
+          if let Some(partial) = word.strip_prefix("${").or(word.strip_prefix('$')) {
+            if word.starts_with("${") {
+              terminator = '}';
+            }
+            let names = state.vars.keys().cloned().chain(std::env::vars().map(|(name, _)| name));
+            (partial, names.collect())
+          } else if input_str[..word_start].trim().is_empty() {
+            let builtins = Builtin::TO_STRING.into_iter().map(str::to_owned);
+            let executables = executables.get(&state.var("PATH").unwrap_or_default());
+            (word, builtins.chain(executables.iter().cloned()).collect())
+          } else {
+            let command = input_str.split_whitespace().next().unwrap_or_default();
+            paths = true;
+            (word, complete_argument(state, command, word))
+          };
+        let mode = state.var("COMPLETION_MODE").unwrap_or_default();
+        let completions: HashSet<&str> = candidates
+          .iter()
+          .map(String::as_str)
+          .filter(|candidate| completion_matches(&mode, partial, candidate))
+          .collect();
+        let mut completions = Vec::from_iter(completions);
+        // Commands that have been run come first, as they're the likelier ones
+        let path = state.var("PATH").unwrap_or_default();
+        let hashed = |name: &str| state.path_index.hashed(name, &path).is_some();
+        completions.sort_by_key(|completion| (!hashed(completion), *completion));
+
+        if completions.len() > 1 {
+          // Only take the shared start if it adds to what was typed, which a looser match may not
+          let prefix = common_prefix(&completions);
+          if prefix.chars().count() > partial.chars().count() {
+            replace_partial(&screen, &mut input, &mut cursor_position, partial, prefix);
+          } else if !repeated_tab {
+            bell(state);
+          } else {
+            let before = &word[..word.len() - partial.len()];
+            let listed = completions.iter().map(|completion| format!("{before}{completion}"));
+            print!("{}", screen.reveal(&input, cursor_position, input.len()));
+            println!("\n{}", listed.collect::<Vec<_>>().join("  "));
+            print!("{}{}", displayed(&prompt(state)), screen.redraw(&input, 0, 0, cursor_position));
+            std::io::stdout().flush().unwrap();
+          }
+        }
+        if completions.len() == 1 {
+          let completion = completions[0];
+          if paths && Path::new(completion).is_dir() {
+            terminator = '/';
+          }
+          let completion = format!("{completion}{terminator}");
+          replace_partial(&screen, &mut input, &mut cursor_position, partial, &completion);
+        } else if completions.is_empty() {
+          bell(state);
+        }
+      }
+      CtrlL => {
+        print!("\x1b[1;1H\x1b[0J"); // Clear screen
+        print!("{}{}", displayed(&prompt(state)), screen.redraw(&input, 0, 0, cursor_position));
+        std::io::stdout().flush().unwrap();
+      }
+      CtrlD => {
+        println!();
+        std::io::stdout().flush().unwrap();
+        input = "exit".chars().collect();
+        break;
+      }
+      UpArrow | DownArrow if history.len() > 1 => {
+        let dx = if let UpArrow = key { -1 } else { 1 };
+        hist_pos = (hist_pos + dx).clamp(0, history.len() as isize - 1);
+        let completion = &history[hist_pos as usize];
+
+        input = completion.chars().collect();
+        print!("{}", screen.redraw(&input, cursor_position, 0, input.len()));
+        cursor_position = input.len();
+        std::io::stdout().flush().unwrap();
+      }
+      UpArrow | DownArrow => continue,
+      Interrupt => {
+        // Drop the line so the main loop gets to run the trap
+        println!();
+        input.clear();
+        break;
+      }
+    }
+    if let Some(before) = before
+      && !matches!(key, Undo)
+      && input != before.0
+    {
+      undo.push(before);
+    }
+  }
+
+  String::from_iter(input)
+}
+
+/// Where the line being edited sits on the terminal, so the cursor can be moved between positions
+/// in it even once it wraps onto more rows
+struct Screen {
+  /// The width of the last line of the prompt, which the input starts after
+  prompt: usize,
+  /// The width of the terminal: `$COLUMNS`, or what the terminal says it is
+  columns: usize,
+  /// The height of the terminal: `$LINES`, or what the terminal says it is
+  rows: usize,
+  /// How much of the line has been written out. What's past it would be below the screen, so it's
+  /// held back until the cursor goes there.
+  drawn: Cell<usize>,
+}
+
+impl Screen {
+  fn new(state: &State) -> Self {
+    let size = |name| state.var(name).and_then(|n| n.parse().ok()).filter(|&n: &usize| n > 0);
+    let (rows, columns) = terminal_size();
+    Self {
+      prompt: prompt_width(&prompt(state)),
+      columns: size("COLUMNS").unwrap_or(columns),
+      rows: size("LINES").unwrap_or(rows),
+      drawn: Cell::new(0),
+    }
+  }
+
+  /// The row, counted from the last row of the prompt, and the column of the cell `position`
+  /// characters into the input
+  fn place(&self, position: usize) -> (usize, usize) {
+    let cell = self.prompt + position;
+    (cell / self.columns, cell % self.columns)
+  }
+
+  /// The escapes that take the cursor from `from` characters into the input to `to`
+  fn move_cursor(&self, from: usize, to: usize) -> String {
+    let (from_row, from_column) = self.place(from);
+    let (to_row, to_column) = self.place(to);
+    let mut res = String::new();
+    match from_row.cmp(&to_row) {
+      Ordering::Greater => res.push_str(&format!("\x1B[{}A", from_row - to_row)),
+      Ordering::Less => res.push_str(&format!("\x1B[{}B", to_row - from_row)),
+      Ordering::Equal => {}
+    }
+    match from_column.cmp(&to_column) {
+      Ordering::Greater => res.push_str(&format!("\x1B[{}D", from_column - to_column)),
+      Ordering::Less => res.push_str(&format!("\x1B[{}C", to_column - from_column)),
+      Ordering::Equal => {}
+    }
+    res
+  }
+
+  /// The escapes that take the cursor from `from` to `to`, writing out the line up to there first
+  /// if some of it was held back
+  fn reveal(&self, input: &[char], from: usize, to: usize) -> String {
+    match to > self.drawn.get() {
+      true => self.redraw(input, from, self.drawn.get(), to),
+      false => self.move_cursor(from, to),
+    }
+  }
+
+  /// The output that rewrites `input` from `start` on, with the cursor first at `from` and left at
+  /// `to`, clearing whatever the line took up before. Only what fits on the screen from the row of
+  /// `to` down is written, so an edit in the middle of a very long line costs a screenful rather
+  /// than all that follows it.
+  fn redraw(&self, input: &[char], from: usize, start: usize, to: usize) -> String {
+    let start = start.min(self.drawn.get());
+    // Stopping a cell short of the last row keeps the terminal from scrolling `to` out of sight
+    let last_row = self.place(to.max(start)).0 + self.rows;
+    let end = (last_row * self.columns - 1).saturating_sub(self.prompt).clamp(start, input.len());
+    let mut res = self.move_cursor(from, start);
+    res.extend(&input[start..end]);
+    // A terminal holds the cursor on the last column after filling it, rather than wrapping, so
+    // push it onto the next row to be where the count puts it
+    if self.place(end).1 == 0 && end > start {
+      res.push_str(" \r");
+    }
+    res.push_str("\x1B[J");
+    res.push_str(&self.move_cursor(end, to));
+    self.drawn.set(end);
+    res
+  }
+}
+
+/// The rows and columns of the terminal on standard output, 24 by 80 if it can't tell
+fn terminal_size() -> (usize, usize) {
+  let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+  match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+    0 if size.ws_row > 0 && size.ws_col > 0 => (size.ws_row.into(), size.ws_col.into()),
+    _ => (24, 80),
+  }
+}
+
+/// Rings the bell in the way `$BELL_STYLE` asks for: `audible`, the default, `visible` to flash the
+/// screen, or `none`
+fn bell(state: &State) {
+  match state.var("BELL_STYLE").as_deref() {
+    Some("none") => return,
+    Some("visible") => {
+      // Reverse video for a moment
+      print!("\x1B[?5h");
+      io::stdout().flush().unwrap();
+      std::thread::sleep(Duration::from_millis(100));
+      print!("\x1B[?5l");
+    }
+    _ => print!("\x07"),
+  }
+  io::stdout().flush().unwrap();
+}
+
+/// Reads a line of input without editing it, `exit` at the end of the input, or nothing if a trapped
+/// signal interrupted the read
+fn read_line() -> String {
+  let mut line = String::new();
+  match io::stdin().read_line(&mut line) {
+    Ok(0) => {
+      println!();
+      "exit".to_owned()
+    }
+    Ok(_) => line.trim_end_matches('\n').to_owned(),
+    Err(e) if e.kind() == io::ErrorKind::Interrupted => String::new(),
+    Err(e) => {
+      eprintln!("{e}");
+      "exit".to_owned()
+    }
+  }
+}
+
+/// Inserts `text` into the line at the cursor, moving the cursor past it and shifting what follows
+/// along on screen
+fn insert_text(screen: &Screen, input: &mut Vec<char>, cursor_position: &mut usize, text: &str) {
+  let start = *cursor_position;
+  for c in text.chars() {
+    input.insert(*cursor_position, c);
+    *cursor_position += 1;
+  }
+  print!("{}", screen.redraw(input, start, start, *cursor_position));
+  io::stdout().flush().unwrap();
+}
+
+/// Takes the characters in `range` out of the line, moving the cursor to where they started, and
+/// gives them back
+fn delete_range(
+  screen: &Screen,
+  input: &mut Vec<char>,
+  cursor_position: &mut usize,
+  range: Range<usize>,
+) -> String {
+  let start = range.start;
+  let deleted = input.drain(range).collect();
+  print!("{}", screen.redraw(input, *cursor_position, start, start));
+  io::stdout().flush().unwrap();
+  *cursor_position = start;
+  deleted
+}
+
+/// Replaces `partial`, which ends at the cursor, with `text`, only retyping from where they differ
+fn replace_partial(
+  screen: &Screen,
+  input: &mut Vec<char>,
+  cursor_position: &mut usize,
+  partial: &str,
+  text: &str,
+) {
+  let same = partial.chars().zip(text.chars()).take_while(|(a, b)| a == b).count();
+  let removed = partial.chars().count() - same;
+  if removed > 0 {
+    input.drain(*cursor_position - removed..*cursor_position);
+    print!("{}", screen.move_cursor(*cursor_position, *cursor_position - removed));
+    *cursor_position -= removed;
+  }
+  insert_text(screen, input, cursor_position, &text.chars().skip(same).collect::<String>());
+}
+
+/// Whether `candidate` completes `partial` in the mode `$COMPLETION_MODE` asks for: starting with
+/// it by default, ignoring case for `nocase`, or containing its characters in order for `fuzzy`
+fn completion_matches(mode: &str, partial: &str, candidate: &str) -> bool {
+  match mode {
+    "nocase" => {
+      let mut candidate = candidate.chars().flat_map(char::to_lowercase);
+      partial.chars().flat_map(char::to_lowercase).all(|c| candidate.next() == Some(c))
+    }
+    "fuzzy" => {
+      let mut candidate = candidate.chars();
+      partial.chars().all(|c| candidate.any(|other| other == c))
+    }
+    _ => candidate.starts_with(partial),
+  }
+}
+
+/// The longest start all of `words` share, made of whole characters
+fn common_prefix<'a>(words: &[&'a str]) -> &'a str {
+  let Some((first, rest)) = words.split_first() else {
+    return "";
+  };
+  let len = rest.iter().fold(first.len(), |len, word| {
+    let same = first.chars().zip(word.chars()).take_while(|(a, b)| a == b);
+    len.min(same.map(|(c, _)| c.len_utf8()).sum())
+  });
+  &first[..len]
+}
+
+/// The paths in the directory `word` is in, only directories if `dirs_only`. Hidden files are left
+/// out unless `word` names them with a leading `.`.
+fn complete_path(word: &str, dirs_only: bool) -> Vec<String> {
+  let (dir, name) = word.split_at(word.rfind('/').map_or(0, |i| i + 1));
+  let Ok(entries) = std::fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+    return vec![];
+  };
+  let entries = entries.flatten().filter_map(|entry| {
+    let file_name = entry.file_name().into_string().ok()?;
+    let hidden = file_name.starts_with('.') && !name.starts_with('.');
+    let wanted = !dirs_only || entry.path().is_dir();
+    (!hidden && wanted).then(|| format!("{dir}{file_name}"))
+  });
+  entries.collect()
+}
+
+/// The words an argument of `command` could be completed to, as registered with `complete`. Without
+/// that, common commands get what they take: directories for `cd` and `pushd`, jobs for `kill`, `fg`
+/// and `bg`, and variables for `unset` and `export`. Anything else gets paths.
+fn complete_argument(state: &State, command: &str, word: &str) -> Vec<String> {
+  let Some(spec) = state.completions.get(command) else {
+    return match command {
+      "cd" | "pushd" => complete_path(word, true),
+      "kill" | "fg" | "bg" => {
+        let jobs = state.jobs.0.iter();
+        jobs.flat_map(|job| [format!("%{}", job.id), job.pid.to_string()]).collect()
+      }
+      "unset" | "export" => state.vars.keys().cloned().collect(),
+      _ => complete_path(word, false),
+    };
+  };
+  let mut candidates = spec.words.clone();
+  if spec.files || spec.dirs {
+    candidates.extend(complete_path(word, !spec.files));
+  }
+  candidates
+}
+
+/// Duplicates `fd` into a file that stays valid after the original is closed
+fn duplicate(fd: RawFd) -> io::Result<File> {
+  Ok(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?.into())
+}
+
+/// Applies `redirects` on top of the given stdin, stdout and stderr
+fn open_redirects(
+  state: &mut State,
+  redirects: &[Redirect],
+  stdin: &mut Option<CommandIn>,
+  stdout: &mut CommandOut,
+  stderr: &mut CommandErr,
+) -> anyhow::Result<()> {
+  for Redirect {
+    fd,
+    op,
+    target,
+  } in redirects
+  {
+    let target = expand_word(target, state)?;
+    let file = match *op {
+      "<" => File::open(&target),
+      ">" => File::create(&target),
+      ">>" => OpenOptions::new().append(true).create(true).open(&target),
+      _ => match target.as_str() {
+        "0" => duplicate(stdin.as_ref().map_or(libc::STDIN_FILENO, AsRawFd::as_raw_fd)),
+        "1" => duplicate(stdout.as_raw_fd()),
+        "2" => duplicate(stderr.as_raw_fd()),
+        _ => return Err(anyhow!("{target}: ambiguous redirect")),
+      },
+    };
+    let file = file.map_err(|e| match e.kind() {
+      io::ErrorKind::NotFound => anyhow!("{target}: No such file or directory"),
+      io::ErrorKind::PermissionDenied => anyhow!("{target}: Permission denied"),
+      _ => anyhow!("{target}: {e}"),
+    })?;
+    match fd {
+      0 => *stdin = Some(CommandIn::File(file)),
+      1 => *stdout = CommandOut::File(file),
+      2 => *stderr = CommandErr::File(file),
+      _ => return Err(anyhow!("{fd}: Bad file descriptor")),
+    }
+  }
+  Ok(())
+}
+
+/// Runs `f` with the shell's own stdin, stdout and stderr pointed at the given ones, for commands
+/// that run inside the shell but can't be handed them directly, like functions
+fn with_fds<T>(
+  stdin: Option<CommandIn>,
+  stdout: CommandOut,
+  stderr: CommandErr,
+  f: impl FnOnce() -> T,
+) -> T {
+  _ = io::stdout().flush();
+  let fds = [
+    (libc::STDIN_FILENO, stdin.as_ref().map(AsRawFd::as_raw_fd)),
+    (libc::STDOUT_FILENO, Some(stdout.as_raw_fd())),
+    (libc::STDERR_FILENO, Some(stderr.as_raw_fd())),
+  ];
+  let mut saved = vec![];
+  for (fd, new) in fds {
+    if let Some(new) = new.filter(|new| *new != fd) {
+      // Keep the original out of the way of the fds children get, and out of the children
+      let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+      unsafe { libc::dup2(new, fd) };
+      saved.push((fd, copy));
+    }
+  }
+  // Drop our handles, so a pipe is closed once the last user of the fd lets go of it
+  drop((stdin, stdout, stderr));
+
+  let res = f();
+
+  _ = io::stdout().flush();
+  for (fd, copy) in saved {
+    unsafe {
+      libc::dup2(copy, fd);
+      libc::close(copy);
+    }
+  }
+  res
+}
+
+/// Replaces aliases in command position with the tokens of their values, checking the value's own
+/// first word too but never expanding an alias inside itself. An alias ending in a space also has
+/// the word after it checked. Tokens from an alias take the span of the alias name. Also gives
+/// `source`, the text the tokens were split from, with the same aliases replaced.
+fn expand_aliases(
+  state: &State,
+  source: &str,
+  tokens: Vec<(Token, Range<usize>)>,
+  seen: &mut Vec<String>,
+) -> (Vec<(Token, Range<usize>)>, String) {
+  let mut res = vec![];
+  let mut text = String::new();
+  let mut copied = 0;
+  let mut command_position = true;
+  for (token, span) in tokens {
+    if command_position
+      && let Token::Word(word) = &token
+      && let Some(name) = word.literal()
+      && !seen.iter().any(|seen| seen == name)
+      && let Some(value) = state.aliases.get(name)
+      && let Ok(value_tokens) = split_spanned(value, &state.options)
+    {
+      seen.push(name.to_owned());
+      let (value_tokens, value_text) = expand_aliases(state, value, value_tokens, seen);
+      res.extend(value_tokens.into_iter().map(|(token, _)| (token, span.clone())));
+      seen.pop();
+      text.push_str(&source[copied..span.start]);
+      text.push_str(&value_text);
+      copied = span.end;
+      command_position = value.ends_with(' ');
+      continue;
+    }
+    command_position = match &token {
+      Token::Operator(op) => *op != ")",
+      Token::Word(word) => matches!(word.literal(), Some("{" | "!")),
+      Token::Redirect(..) => false,
+    };
+    res.push((token, span));
+  }
+  text.push_str(&source[copied..]);
+  (res, text)
+}
+
+/// Expands and runs a simple command, returning the child if it spawned one
+fn run_simple(
+  state: &mut State,
+  command: &SimpleCommand,
+  mut stdin: Option<CommandIn>,
+  mut stdout: CommandOut,
+) -> anyhow::Result<Option<libc::pid_t>> {
+  let mut stderr = CommandErr::Stderr(io::stderr());
+  state.line_number = state.input_line + command.line - 1;
+  let mut words = command.words.clone();
+  let expanded = expand_assignments(&mut words, state)
+    .and_then(|assignments| {
+      for (name, elements) in &command.arrays {
+        let elements = expand(elements.clone(), state)?;
+        state.set_array(name, elements)?;
+      }
+      Ok((assignments, expand(words, state)?))
+    })
+    .and_then(|expanded| {
+      open_redirects(state, &command.redirects, &mut stdin, &mut stdout, &mut stderr)?;
+      Ok(expanded)
+    });
+  let (assignments, mut args) = match expanded {
+    Ok(expanded) => expanded,
+    Err(e) => {
+      eprintln!("{e}");
+      return Err(Status(1).into());
+    }
+  };
+
+  if args.is_empty() {
+    for (name, value) in assignments {
+      if let Err(e) = state.set_var(&name, value) {
+        eprintln!("{e}");
+        return Err(Status(1).into());
+      }
+    }
+    state.status = 0;
+    return Ok(None);
+  }
+  let readonly = |name: &str| state.vars.get(name).is_some_and(|var| var.readonly);
+  if let Some((name, _)) = assignments.iter().find(|(name, _)| readonly(name)) {
+    eprintln!("{name}: readonly variable");
+    return Err(Status(1).into());
+  }
+  let name = args.remove(0);
+  let command = Command {
+    kind: CommandKind::parse(&name, state),
+    args,
+    assignments,
+  };
+  // `exec` in a pipeline takes over only its own stage, so it runs in a copy of the shell
+  let piped = matches!(stdin, Some(CommandIn::Pipe(_))) || matches!(stdout, CommandOut::Pipe(_));
+  if piped && let CommandKind::Builtin(Builtin::Exec) = command.kind {
+    _ = io::stdout().flush();
+    return match unsafe { libc::fork() } {
+      -1 => {
+        eprintln!("fork: {}", io::Error::last_os_error());
+        Err(Status(1).into())
+      }
+      0 => {
+        state.jobs = Default::default();
+        state.interactive = false;
+        let status = match command.run(state, stdout, stderr, stdin) {
+          Ok(Some(pid)) => wait(pid),
+          Ok(None) => state.status,
+          Err(e) => e.downcast_ref::<Status>().map_or(1, |status| status.0),
+        };
+        _ = io::stdout().flush();
+        unsafe { libc::_exit(status) };
+      }
+      pid => Ok(Some(pid)),
+    };
+  }
+  command.run(state, stdout, stderr, stdin)
+}
+
+/// Runs `node` with the given stdin and stdout, which default to the shell's own. Commands run
+/// inside the shell leave their exit status in `state.status`, while spawned ones are returned.
+fn run_node(
+  state: &mut State,
+  node: &Node,
+  stdin: Option<CommandIn>,
+  stdout: Option<CommandOut>,
+) -> Option<libc::pid_t> {
+  let stdout = stdout.unwrap_or(CommandOut::Stdout(io::stdout()));
+  let res = match node {
+    Node::Simple(command) => run_simple(state, command, stdin, stdout),
+    Node::Compound(compound, redirects) => {
+      let mut stdin = stdin;
+      let mut stdout = stdout;
+      let mut stderr = CommandErr::Stderr(io::stderr());
+      match open_redirects(state, redirects, &mut stdin, &mut stdout, &mut stderr) {
+        Ok(()) => {
+          with_fds(stdin, stdout, stderr, || run_compound(state, compound));
+          Ok(None)
+        }
+        Err(e) => {
+          eprintln!("{e}");
+          Err(Status(1).into())
+        }
+      }
+    }
+    Node::Function(name, body) => {
+      state.functions.insert(name.clone(), body.clone());
+      state.status = 0;
+      Ok(None)
+    }
+  };
+  // The command has what it needs of the pipes to process substitutions, whether it opened their
+  // paths or inherited them
+  state.substitution_fds.clear();
+  match res {
+    Ok(pid) => pid,
+    Err(e) => {
+      // Errors were already reported where they happened
+      state.status = e.downcast_ref::<Status>().map_or(1, |status| status.0);
+      None
+    }
+  }
+}
+
+fn run_compound(state: &mut State, compound: &Compound) {
+  match compound {
+    Compound::Group(list) => run_list(state, list),
+    Compound::Loop {
+      until,
+      condition,
+      body,
+    } => {
+      let mut status = 0;
+      state.loop_depth += 1;
+      loop {
+        run_list(state, condition);
+        if loop_left(state) || (state.status == 0) == *until {
+          break;
+        }
+        run_list(state, body);
+        status = state.status;
+        if loop_left(state) {
+          break;
+        }
+      }
+      state.loop_depth -= 1;
+      state.status = status;
+    }
+    Compound::Select {
+      name,
+      words,
+      body,
+    } => {
+      let items = match words {
+        Some(words) => match expand(words.clone(), state) {
+          Ok(items) => items,
+          Err(e) => {
+            eprintln!("{e}");
+            state.status = 1;
+            return;
+          }
+        },
+        None => state.positional.clone(),
+      };
+      let width = items.len().to_string().len();
+      let mut status = 0;
+      // The menu is shown at first and again after an empty reply
+      let mut menu = true;
+      state.loop_depth += 1;
+      while !items.is_empty() {
+        if menu {
+          for (i, item) in items.iter().enumerate() {
+            eprintln!("{:>width$}) {item}", i + 1);
+          }
+        }
+        eprint!("{}", state.var("PS3").unwrap_or_else(|| "#? ".to_owned()));
+        let stdout = CommandOut::Stdout(io::stdout());
+        let stderr = CommandErr::Stderr(io::stderr());
+        // The input running out ends the loop
+        if Builtin::Read.run(state, stdout, stderr, None, vec!["-r".to_owned()]).is_err() {
+          eprintln!();
+          break;
+        }
+        let reply = state.var("REPLY").unwrap_or_default();
+        menu = reply.is_empty();
+        if menu {
+          continue;
+        }
+        let index = reply.trim().parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+        let choice = index.and_then(|i| items.get(i)).cloned().unwrap_or_default();
+        if let Err(e) = state.set_var(name, choice) {
+          eprintln!("{e}");
+          status = 1;
+          break;
+        }
+        run_list(state, body);
+        status = state.status;
+        if loop_left(state) {
+          break;
+        }
+      }
+      state.loop_depth -= 1;
+      state.status = status;
+    }
+  }
+}
+
+/// Whether the loop being run is to be left, for `exit` or a `break` or `continue` that reaches past
+/// it. One that stops at it is dealt with, leaving the loop to go on.
+fn loop_left(state: &mut State) -> bool {
+  match state.control_flow {
+    ControlFlow::Break(n) | ControlFlow::Continue(n) if n > 1 => {
+      state.control_flow = match state.control_flow {
+        ControlFlow::Break(_) => ControlFlow::Break(n - 1),
+        _ => ControlFlow::Continue(n - 1),
+      };
+      true
+    }
+    ControlFlow::Break(_) => {
+      state.control_flow = ControlFlow::Repl;
+      true
+    }
+    ControlFlow::Continue(_) => {
+      state.control_flow = ControlFlow::Repl;
+      false
+    }
+    ControlFlow::Repl => false,
+    ControlFlow::Exit => true,
+  }
+}
+
+/// Waits for `pid` to finish, returning its exit status
+fn wait(pid: libc::pid_t) -> i32 {
+  let mut status = 0;
+  unsafe { libc::waitpid(pid, &mut status, 0) };
+  jobs::exit_status(status)
+}
+
+/// The user and system CPU time used by the shell and the children it has waited for
+fn cpu_times() -> (Duration, Duration) {
+  let mut total = (Duration::ZERO, Duration::ZERO);
+  for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+    let usage = unsafe {
+      let mut usage = std::mem::zeroed::<libc::rusage>();
+      libc::getrusage(who, &mut usage);
+      usage
+    };
+    let duration =
+      |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    total.0 += duration(usage.ru_utime);
+    total.1 += duration(usage.ru_stime);
+  }
+  total
+}
+
+/// Formats a duration like `time` does, e.g. `0m1.250s`
+fn minutes(duration: Duration) -> String {
+  let secs = duration.as_secs_f64();
+  format!("{}m{:.3}s", (secs / 60.0) as u64, secs % 60.0)
+}
+
+fn run_pipeline(state: &mut State, pipeline: &Pipeline) {
+  let start = pipeline.timed.then(|| (Instant::now(), cpu_times()));
+  // The child of each stage that spawned one, or the status of one that ran in the shell
+  let mut stages = vec![];
+  let mut stdin = None;
+  for (i, node) in pipeline.commands.iter().enumerate() {
+    let (next_stdin, stdout) = if i + 1 < pipeline.commands.len() {
+      let (reader, writer) = io::pipe().unwrap();
+      (Some(CommandIn::Pipe(reader)), Some(CommandOut::Pipe(writer)))
+    } else {
+      (None, None)
+    };
+    let child = run_node(state, node, stdin.take(), stdout);
+    stages.push((child, state.status));
+    stdin = next_stdin;
+  }
+
+  let statuses = stages.into_iter().map(|(child, status)| child.map_or(status, wait));
+  state.pipe_status = statuses.collect();
+  for pid in std::mem::take(&mut state.substitutions) {
+    wait(pid);
+  }
+  state.status = match state.options.pipefail {
+    true => state.pipe_status.iter().rev().find(|status| **status != 0),
+    false => state.pipe_status.last(),
+  }
+  .copied()
+  .unwrap_or_default();
+  if pipeline.negated {
+    state.status = (state.status == 0) as i32;
+  }
+
+  if let Some((start, (user, sys))) = start {
+    let (real, (end_user, end_sys)) = (start.elapsed(), cpu_times());
+    eprintln!("\nreal\t{}", minutes(real));
+    eprintln!("user\t{}", minutes(end_user.saturating_sub(user)));
+    eprintln!("sys\t{}", minutes(end_sys.saturating_sub(sys)));
+  }
+}
+
+fn run_and_or(state: &mut State, and_or: &AndOr) {
+  run_pipeline(state, &and_or.first);
+  for (connector, pipeline) in &and_or.rest {
+    if !matches!(state.control_flow, ControlFlow::Repl) {
+      break;
+    }
+    let run = match connector {
+      Connector::And => state.status == 0,
+      Connector::Or => state.status != 0,
+    };
+    if run {
+      run_pipeline(state, pipeline);
+    }
+  }
+}
+
+/// Runs `and_or` in a copy of the shell without waiting for it, adding it to the jobs table
+fn run_background(state: &mut State, and_or: &AndOr) {
+  _ = io::stdout().flush();
+  match unsafe { libc::fork() } {
+    -1 => {
+      eprintln!("fork: {}", io::Error::last_os_error());
+      state.status = 1;
+    }
+    0 => {
+      // The jobs belong to the parent, which is the one that waits for them
+      state.jobs = Default::default();
+      state.interactive = false;
+      run_and_or(state, and_or);
+      _ = io::stdout().flush();
+      unsafe { libc::_exit(state.status) };
+    }
+    pid => {
+      let id = state.jobs.add(pid, and_or.text.clone());
+      state.last_background = Some(pid);
+      if state.interactive {
+        eprintln!("[{id}] {pid}");
+      }
+      state.status = 0;
+    }
+  }
+}
+
+fn run_list(state: &mut State, list: &List) {
+  for and_or in &list.0 {
+    // `exit`, `break` and `continue` skip the rest
+    if !matches!(state.control_flow, ControlFlow::Repl) {
+      break;
+    }
+    if state.options.noexec && !state.interactive {
+      break;
+    }
+    if and_or.background {
+      run_background(state, and_or);
+    } else {
+      run_and_or(state, and_or);
+    }
+  }
+}
+
+/// Prints the line of `input` containing the byte offset `pos`, with a caret under it
+fn print_caret(input: &str, pos: usize) {
+  let line_start = input[..pos].rfind('\n').map_or(0, |i| i + 1);
+  let line_end = input[pos..].find('\n').map_or(input.len(), |i| pos + i);
+  let column = input[line_start..pos].chars().count();
+  eprintln!("{}", &input[line_start..line_end]);
+  eprintln!("{}^", " ".repeat(column));
+}
+
+/// Parses and runs a line of input, leaving its exit status in `state.status`
+fn run_line(state: &mut State, input: &str) {
+  // Without a person watching, say where in the input the error is
+  let line = |pos: usize| match state.interactive {
+    true => String::new(),
+    false => format!("line {}: ", state.input_line + input[..pos].matches('\n').count()),
+  };
+  let tokens = match split_spanned(input, &state.options) {
+    Ok(tokens) => tokens,
+    Err(e) => {
+      eprintln!("{}syntax error: {e}", line(e.pos));
+      print_caret(input, e.pos);
+      state.status = 2;
+      return;
+    }
+  };
+  match parse::parse(input, expand_aliases(state, input, tokens, &mut vec![]).0) {
+    Ok(list) => run_list(state, &list),
+    Err(parse::SyntaxError {
+      token,
+      pos,
+    }) => {
+      eprintln!("{}syntax error near unexpected token `{token}'", line(pos));
+      print_caret(input, pos);
+      state.status = 2;
+    }
+  }
+}
+
+/// Whether `input` stops partway through a command, like inside quotes or an unclosed `{`, so it
+/// needs more lines before it can run
+fn incomplete(state: &State, input: &str) -> bool {
+  match split_spanned(input, &state.options) {
+    Ok(tokens) => matches!(
+      parse::parse(input, expand_aliases(state, input, tokens, &mut vec![]).0),
+      Err(parse::SyntaxError { token, .. }) if token == "end of file"
+    ),
+    Err(_) => true,
+  }
+}
+
+/// Renders `$PS1`, `$ ` by default, expanding `\w` and `\W` to the working directory (the full path
+/// and its last component, with `$HOME` shown as `~`), `\u` to the user, `\h` to the host name, `\$`
+/// to `#` for root and `$` otherwise, `\n` to a new line, `\e` to an escape, `\a` to a bell and `\\`
+/// to a backslash. What's between `\[` and `\]`, like color escapes, takes up no room on screen,
+/// and is kept between `\x01` and `\x02` as readline does. `\e]0;title\a` sets the terminal's title.
+fn prompt(state: &State) -> String {
+  let ps1 = state.var("PS1").unwrap_or_else(|| "$ ".to_owned());
+  let pwd = state.var("PWD").unwrap_or_default();
+  let home = state.var("HOME").filter(|home| !home.is_empty() && home != "/");
+  let tilde = match home.as_deref().and_then(|home| pwd.strip_prefix(home)) {
+    Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+    _ => pwd.clone(),
+  };
+
+  let mut res = String::new();
+  let mut chars = ps1.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      res.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('w') => res.push_str(&tilde),
+      Some('W') if tilde == "~" || pwd == "/" => res.push_str(&tilde),
+      Some('W') => res.push_str(pwd.rsplit('/').next().unwrap_or_default()),
+      Some('u') => res.push_str(&state.var("USER").unwrap_or_default()),
+      Some('h') => {
+        let mut name = [0u8; 256];
+        unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) };
+        let name = String::from_utf8_lossy(&name);
+        res.push_str(name.split(['.', '\0']).next().unwrap_or_default());
+      }
+      Some('$') => res.push(if unsafe { libc::geteuid() } == 0 { '#' } else { '$' }),
+      Some('n') => res.push('\n'),
+      Some('e') => res.push('\x1B'),
+      Some('a') => res.push('\x07'),
+      Some('[') => res.push('\x01'),
+      Some(']') => res.push('\x02'),
+      Some('\\') => res.push('\\'),
+      Some(c) => {
+        res.push('\\');
+        res.push(c);
+      }
+      None => res.push('\\'),
+    }
+  }
+  res
+}
+
+/// `prompt` as it's printed, without the markers around what takes up no room. Titles are only for
+/// a terminal, so they're left out when the output goes elsewhere.
+fn displayed(prompt: &str) -> String {
+  let prompt = prompt.replace(['\x01', '\x02'], "");
+  if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+    return prompt;
+  }
+  let mut res = String::new();
+  let mut rest = prompt.as_str();
+  while let Some(start) = rest.find("\x1B]") {
+    res.push_str(&rest[..start]);
+    rest = rest[start..].split_once('\x07').map_or("", |(_, after)| after);
+  }
+  res.push_str(rest);
+  res
+}
+
+/// How many columns the last line of `prompt` takes up, leaving out what's marked as taking none
+fn prompt_width(prompt: &str) -> usize {
+  let line = prompt.rsplit('\n').next().unwrap_or_default();
+  let mut invisible = false;
+  // A title takes up no room whether or not it's marked
+  let mut title = false;
+  let mut width = 0;
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '\x01' => invisible = true,
+      '\x02' => invisible = false,
+      '\x1B' if chars.peek() == Some(&']') => title = true,
+      '\x07' if title => title = false,
+      _ => width += !(invisible || title) as usize,
+    }
+  }
+  width
+}
+
+/// Runs the traps set for `signals`, keeping `$?` intact
+fn run_traps(state: &mut State, signals: impl IntoIterator<Item = i32>) {
+  for signal in signals {
+    if let Some(command) = state.traps.get(&signal).cloned() {
+      let status = state.status;
+      // The EXIT trap runs once the shell is already on its way out
+      let control_flow = std::mem::replace(&mut state.control_flow, ControlFlow::Repl);
+      run_line(state, &command);
+      state.status = status;
+      if let ControlFlow::Repl = state.control_flow {
+        state.control_flow = control_flow;
+      }
+    }
+  }
+}
+
+/// A shell that runs the lines it's handed, as `-c` and scripts do, or reads them from a prompt,
+/// keeping its state between them. Lines can come from anywhere, so it can be embedded.
+pub struct Shell {
+  state: State,
+}
+
+impl Default for Shell {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Shell {
+  /// An interactive shell on the terminal of standard input, if there is one, with the history in
+  /// `$HISTFILE`
+  pub fn new() -> Self {
+    signal::set_disposition(libc::SIGCHLD, signal::Disposition::Default);
+    let mut state = State::new(Terminal::new(io::stdin().as_raw_fd()));
+    (state.history, state.history_times) = std::env::var("HISTFILE")
+      .ok()
+      .and_then(|path| std::fs::read_to_string(path).ok())
+      .map(|content| history::load(&content))
+      .unwrap_or_default();
+    state.history_append_position = state.history.len();
+    Self {
+      state,
+    }
+  }
+
+  /// Sets whether there's someone at a prompt, as opposed to `-c` or a script
+  pub fn set_interactive(&mut self, interactive: bool) {
+    self.state.interactive = interactive;
+  }
+
+  /// Turns the option `name`, as `set -o` and `shopt` know it, on or off. False if there's no
+  /// such option.
+  pub fn set_option(&mut self, name: &str, on: bool) -> bool {
+    self.state.options.get_mut(name).map(|option| *option = on).is_some()
+  }
+
+  /// The value of the variable `name`, if it's set
+  pub fn var(&self, name: &str) -> Option<String> {
+    self.state.var(name)
+  }
+
+  /// Runs `/etc/profile` and the user's profile, as a login shell does before anything else
+  pub fn source_profile(&mut self) {
+    source_profile(&mut self.state);
+  }
+
+  /// Runs `line`, which may be several lines, returning its exit status
+  pub fn run_line(&mut self, line: &str) -> i32 {
+    run_line(&mut self.state, line);
+    self.state.input_line += line.lines().count().max(1);
+    self.state.status
+  }
+
+  /// Runs the script `content` one complete command at a time, as read, so a syntax error only
+  /// stops the command it's in. With `-n` the whole script is parsed at once, to check it all.
+  pub fn run_script(&mut self, content: &str) -> i32 {
+    if self.state.options.noexec {
+      return self.run_line(content);
+    }
+    let mut command = String::new();
+    for line in content.split_inclusive('\n') {
+      command.push_str(line);
+      if incomplete(&self.state, &command) {
+        continue;
+      }
+      self.run_line(&std::mem::take(&mut command));
+      if let ControlFlow::Exit = self.state.control_flow {
+        return self.state.status;
+      }
+    }
+    // Whatever's left runs out of input, which reports what's unterminated
+    if !command.is_empty() {
+      self.run_line(&command);
+    }
+    self.state.status
+  }
+
+  /// Runs the script at `path` with `args` as its positional parameters, returning its exit
+  /// status, or 127 if it can't be read
+  pub fn run_file(&mut self, path: &str, args: &[String]) -> i32 {
+    self.state.positional = args.to_vec();
+    match std::fs::read_to_string(path) {
+      Ok(content) => self.run_script(&content),
+      Err(e) => {
+        match e.kind() {
+          io::ErrorKind::NotFound => eprintln!("{path}: No such file or directory"),
+          io::ErrorKind::PermissionDenied => eprintln!("{path}: Permission denied"),
+          _ => eprintln!("{path}: {e}"),
+        }
+        self.state.status = 127;
+        127
+      }
+    }
+  }
+
+  /// Prompts for lines and runs them until the shell is asked to exit
+  pub fn run_interactive(&mut self) {
+    let mut executables = Executables::default();
+    // Without raw mode, keys can't be taken one at a time, so lines are read as the terminal or
+    // pipe hands them over, with no editing
+    let raw = self.state.terminal.set_raw();
+
+    while let ControlFlow::Repl = &self.state.control_flow {
+      let state = &mut self.state;
+      run_traps(state, signal::take_pending());
+      // Say which jobs finished since the last prompt, as `jobs` would
+      if signal::take_children() {
+        state.jobs.update();
+      }
+      for (mark, job) in state.jobs.take_done() {
+        eprintln!("[{}]{mark}  {:<24}{}", job.id, job.status.to_string(), job.command);
+      }
+      print!("{}", displayed(&prompt(state)));
+      io::stdout().flush().unwrap();
+
+      let input: String = match raw {
+        true => handle_input(state, io::stdin(), &mut executables),
+        false => read_line(),
+      };
+      if input.is_empty() {
+        continue;
+      }
+      let input = match state.options.histexpand {
+        true => match history::expand(&input, &state.history) {
+          // Show what a reference to history stood for before running it
+          Ok(expanded) if expanded != input => {
+            println!("{expanded}");
+            expanded
+          }
+          Ok(input) => input,
+          Err(e) => {
+            eprintln!("{e}");
+            state.status = 1;
+            continue;
+          }
+        },
+        false => input,
+      };
+      // Likewise for aliases, though it's the line as typed that goes in the history
+      if let Ok(tokens) = split_spanned(&input, &state.options) {
+        let (_, expanded) = expand_aliases(state, &input, tokens, &mut vec![]);
+        if expanded != input {
+          println!("{expanded}");
+        }
+      }
+      state.push_history(input.clone());
+      self.run_line(&input);
+    }
+  }
+
+  /// Leaves the shell as `exit` does, with `$?`
+  pub fn exit(&mut self) -> ! {
+    shutdown(&mut self.state)
+  }
+}
+
+/// Runs `/etc/profile`, then the first of `~/.rustsh_profile` and `~/.profile` there is, as a login
+/// shell does before anything else
+fn source_profile(state: &mut State) {
+  let home = state.var("HOME").unwrap_or_default();
+  let personal = [".rustsh_profile", ".profile"].map(|name| Path::new(&home).join(name));
+  let personal = personal.into_iter().find(|path| !home.is_empty() && path.is_file());
+  for path in once(PathBuf::from("/etc/profile")).chain(personal) {
+    if let Ok(content) = std::fs::read_to_string(&path) {
+      run_line(state, &content);
+    }
+  }
+}
+
+/// Leaves the shell, however it was asked to: runs the EXIT trap, hangs up on interactive jobs,
+/// appends the session's new history to `$HISTFILE`, leaves the terminal as it was found and exits
+/// with `$?`
+fn shutdown(state: &mut State) -> ! {
+  run_traps(state, [signal::EXIT]);
+  if state.interactive {
+    state.jobs.update();
+    state.jobs.hang_up();
+  }
+
+  if let Some(histfile) = state.var("HISTFILE").filter(|histfile| !histfile.is_empty()) {
+    let history = history::save(
+      &state.history[state.history_append_position..],
+      &state.history_times[state.history_append_position..],
+      state.var("HISTTIMEFORMAT").is_some(),
+    );
+    let res = OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(&histfile)
+      .and_then(|mut file| file.write_all(history.as_bytes()));
+    if let Err(e) = res {
+      eprintln!("{histfile}: {e}");
+    }
+  }
+
+  state.terminal.restore();
+  _ = io::stdout().flush();
+  std::process::exit(state.status)
+}
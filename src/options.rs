@@ -0,0 +1,74 @@
+/// The names of the options, along with whether `set -o` knows them rather than only `shopt`
+pub const OPTIONS: [(&str, bool); 9] = [
+  ("extglob", false),
+  ("failglob", false),
+  ("histexpand", true),
+  ("igncr", false),
+  ("noexec", true),
+  ("nounset", true),
+  ("nullglob", false),
+  ("pipefail", true),
+  ("posix", true),
+];
+
+/// The options toggled with `set -o`/`set +o` and `shopt -s`/`shopt -u`
+#[derive(Clone, Debug)]
+pub struct ShellOptions {
+  /// `shopt -s extglob`: patterns can have groups like `@(a|b)` and `!(*.txt)`
+  pub extglob: bool,
+  /// `shopt -s failglob`: a pattern that matches no paths is an error
+  pub failglob: bool,
+  /// `set -H`: `!` refers to history in interactive input
+  pub histexpand: bool,
+  /// `shopt -s igncr`: `read` drops the carriage return of lines ending in CRLF
+  pub igncr: bool,
+  /// `set -n`: commands are read but not run, to check their syntax. Interactive shells ignore it.
+  pub noexec: bool,
+  /// `set -u`: expanding a parameter that isn't set is an error
+  pub nounset: bool,
+  /// `shopt -s nullglob`: a pattern that matches no paths expands to nothing
+  pub nullglob: bool,
+  /// `set -o pipefail`: a pipeline fails with the status of its last stage that failed
+  pub pipefail: bool,
+  /// `set -o posix`: `echo` takes no options, the `$` of `$'...'` is literal and there's no
+  /// `<(command)` or `>(command)` process substitution, as in a POSIX shell
+  pub posix: bool,
+}
+
+impl Default for ShellOptions {
+  fn default() -> Self {
+    Self {
+      extglob: false,
+      failglob: false,
+      histexpand: true,
+      igncr: false,
+      noexec: false,
+      nounset: false,
+      nullglob: false,
+      pipefail: false,
+      posix: false,
+    }
+  }
+}
+
+impl ShellOptions {
+  /// The option called `name`, or `None` if there's no such option
+  pub fn get(&self, name: &str) -> Option<bool> {
+    self.clone().get_mut(name).map(|on| *on)
+  }
+
+  pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+    match name {
+      "extglob" => Some(&mut self.extglob),
+      "failglob" => Some(&mut self.failglob),
+      "histexpand" => Some(&mut self.histexpand),
+      "igncr" => Some(&mut self.igncr),
+      "noexec" => Some(&mut self.noexec),
+      "nounset" => Some(&mut self.nounset),
+      "nullglob" => Some(&mut self.nullglob),
+      "pipefail" => Some(&mut self.pipefail),
+      "posix" => Some(&mut self.posix),
+      _ => None,
+    }
+  }
+}
@@ -0,0 +1,406 @@
+use std::{ops::Range, rc::Rc};
+
+use crate::expand::{assignment, is_name};
+use crate::split::{Token, Word};
+
+/// The reserved words, which are only special as the first word of a command
+pub const KEYWORDS: [&str; 10] =
+  ["!", "{", "}", "do", "done", "in", "select", "time", "until", "while"];
+
+/// A syntax error, holding the token the parser didn't expect and its byte offset
+#[derive(Debug)]
+pub struct SyntaxError {
+  pub token: String,
+  pub pos: usize,
+}
+
+/// `[fd]op target`, e.g. `2>> log`
+#[derive(Clone, Debug)]
+pub struct Redirect {
+  pub fd: i32,
+  pub op: &'static str,
+  pub target: Word,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SimpleCommand {
+  pub words: Vec<Word>,
+  /// `name=(elements)` array assignments, which come before the command's name
+  pub arrays: Vec<(String, Vec<Word>)>,
+  pub redirects: Vec<Redirect>,
+  /// The line of the input it starts on, counting from 1
+  pub line: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum Compound {
+  /// `{ list; }`
+  Group(List),
+  /// `while condition; do body; done`, or `until` when `until` is set
+  Loop {
+    until: bool,
+    condition: List,
+    body: List,
+  },
+  /// `select name in words; do body; done`, or `select name; do body; done` to choose from `$@`
+  Select {
+    name: String,
+    words: Option<Vec<Word>>,
+    body: List,
+  },
+}
+
+#[derive(Clone, Debug)]
+pub enum Node {
+  Simple(SimpleCommand),
+  Compound(Compound, Vec<Redirect>),
+  /// `name() body`
+  Function(String, Rc<Node>),
+}
+
+/// Commands joined by `|`, optionally preceded by `!` to negate the status
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+  /// Whether it's preceded by `time`, to report how long it took
+  pub timed: bool,
+  pub negated: bool,
+  pub commands: Vec<Node>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connector {
+  And,
+  Or,
+}
+
+/// Pipelines joined by `&&` and `||`
+#[derive(Clone, Debug)]
+pub struct AndOr {
+  pub first: Pipeline,
+  pub rest: Vec<(Connector, Pipeline)>,
+  /// Whether it ended in `&`, to run without waiting for it
+  pub background: bool,
+  /// The input it was parsed from, as `jobs` shows it
+  pub text: String,
+}
+
+/// Commands separated by `;`, `&` or newlines
+#[derive(Clone, Debug, Default)]
+pub struct List(pub Vec<AndOr>);
+
+struct Parser<'a> {
+  input: &'a str,
+  tokens: Vec<(Token, Range<usize>)>,
+  pos: usize,
+  /// Byte offsets of the starts of the input's lines after the first
+  line_starts: Vec<usize>,
+}
+
+impl Parser<'_> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos).map(|(token, _)| token)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    let token = self.peek().cloned();
+    self.pos += 1;
+    token
+  }
+
+  fn eat(&mut self, operator: &str) -> bool {
+    let found = matches!(self.peek(), Some(Token::Operator(op)) if *op == operator);
+    self.pos += found as usize;
+    found
+  }
+
+  /// Whether the next token is the unquoted reserved word `keyword`
+  fn at_keyword(&self, keyword: &str) -> bool {
+    matches!(self.peek(), Some(Token::Word(word)) if word.literal() == Some(keyword))
+  }
+
+  fn skip_newlines(&mut self) {
+    while self.eat("\n") {}
+  }
+
+  fn unexpected(&self) -> SyntaxError {
+    let token = match self.peek() {
+      None => "end of file".to_owned(),
+      Some(Token::Operator("\n")) => "newline".to_owned(),
+      Some(Token::Operator(op)) => op.to_string(),
+      Some(Token::Redirect(_, op)) => op.to_string(),
+      Some(Token::Word(word)) => word.literal().unwrap_or("word").to_owned(),
+    };
+    let pos = match self.tokens.get(self.pos) {
+      Some((_, span)) => span.start,
+      None => self.tokens.last().map_or(0, |(_, span)| span.end),
+    };
+    SyntaxError {
+      token,
+      pos,
+    }
+  }
+
+  /// Whether the next token is a reserved word that ends a list, like `}` or `done`
+  fn at_terminator(&self) -> bool {
+    ["}", "do", "done"].iter().any(|keyword| self.at_keyword(keyword))
+  }
+
+  /// Consumes the reserved word `keyword`, or fails if it's not next
+  fn expect(&mut self, keyword: &str) -> Result<(), SyntaxError> {
+    if !self.at_keyword(keyword) {
+      return Err(self.unexpected());
+    }
+    self.pos += 1;
+    Ok(())
+  }
+
+  /// Parses commands up to the end of the input or a terminating reserved word, which is left for
+  /// the caller
+  fn list(&mut self) -> Result<List, SyntaxError> {
+    let mut list = vec![];
+    loop {
+      self.skip_newlines();
+      if self.peek().is_none() || self.at_terminator() {
+        return Ok(List(list));
+      }
+      let mut and_or = self.and_or()?;
+      and_or.background = self.eat("&");
+      if and_or.background {
+        and_or.text.push_str(" &");
+      }
+      let separated = and_or.background || self.eat(";") || self.eat("\n");
+      list.push(and_or);
+      if !separated {
+        return match self.peek() {
+          None => Ok(List(list)),
+          _ if self.at_terminator() => Ok(List(list)),
+          _ => Err(self.unexpected()),
+        };
+      }
+    }
+  }
+
+  /// Parses a list that must contain at least one command
+  fn nonempty_list(&mut self) -> Result<List, SyntaxError> {
+    let list = self.list()?;
+    if list.0.is_empty() {
+      return Err(self.unexpected());
+    }
+    Ok(list)
+  }
+
+  fn and_or(&mut self) -> Result<AndOr, SyntaxError> {
+    let start = self.tokens.get(self.pos).map_or(0, |(_, span)| span.start);
+    let first = self.pipeline()?;
+    let mut rest = vec![];
+    loop {
+      let connector = if self.eat("&&") {
+        Connector::And
+      } else if self.eat("||") {
+        Connector::Or
+      } else {
+        let end = self.tokens.get(self.pos - 1).map_or(start, |(_, span)| span.end);
+        return Ok(AndOr {
+          first,
+          rest,
+          background: false,
+          text: self.input[start..end].to_owned(),
+        });
+      };
+      self.skip_newlines();
+      rest.push((connector, self.pipeline()?));
+    }
+  }
+
+  fn pipeline(&mut self) -> Result<Pipeline, SyntaxError> {
+    let timed = self.at_keyword("time");
+    self.pos += timed as usize;
+    let negated = self.at_keyword("!");
+    self.pos += negated as usize;
+    let mut commands = vec![self.command()?];
+    while self.eat("|") {
+      self.skip_newlines();
+      commands.push(self.command()?);
+    }
+    Ok(Pipeline {
+      timed,
+      negated,
+      commands,
+    })
+  }
+
+  fn command(&mut self) -> Result<Node, SyntaxError> {
+    if ["{", "while", "until", "select"].iter().any(|keyword| self.at_keyword(keyword)) {
+      return self.compound();
+    }
+    if let (Some(Token::Word(name)), Some((Token::Operator("("), _))) =
+      (self.peek(), self.tokens.get(self.pos + 1))
+      && self.array_assignment().is_none()
+    {
+      let name = name.literal().ok_or_else(|| self.unexpected())?.to_owned();
+      self.pos += 2;
+      if !self.eat(")") {
+        return Err(self.unexpected());
+      }
+      self.skip_newlines();
+      if !self.at_keyword("{") {
+        return Err(self.unexpected());
+      }
+      return Ok(Node::Function(name, Rc::new(self.compound()?)));
+    }
+    self.simple_command()
+  }
+
+  /// Parses a compound command starting at its reserved word, along with its redirections
+  fn compound(&mut self) -> Result<Node, SyntaxError> {
+    let compound = match self.next() {
+      Some(Token::Word(word)) if word.literal() == Some("{") => {
+        let list = self.nonempty_list()?;
+        self.expect("}")?;
+        Compound::Group(list)
+      }
+      Some(Token::Word(word)) if word.literal() == Some("select") => {
+        let name = match self.peek() {
+          Some(Token::Word(name)) => name.literal().filter(|name| is_name(name)),
+          _ => None,
+        };
+        let name = name.ok_or_else(|| self.unexpected())?.to_owned();
+        self.pos += 1;
+        self.skip_newlines();
+        let mut words = None;
+        if self.at_keyword("in") {
+          self.pos += 1;
+          let mut list = vec![];
+          while let Some(Token::Word(word)) = self.peek() {
+            list.push(word.clone());
+            self.pos += 1;
+          }
+          words = Some(list);
+        }
+        _ = self.eat(";");
+        self.skip_newlines();
+        self.expect("do")?;
+        let body = self.nonempty_list()?;
+        self.expect("done")?;
+        Compound::Select {
+          name,
+          words,
+          body,
+        }
+      }
+      Some(Token::Word(word)) => {
+        let until = word.literal() == Some("until");
+        let condition = self.nonempty_list()?;
+        self.expect("do")?;
+        let body = self.nonempty_list()?;
+        self.expect("done")?;
+        Compound::Loop {
+          until,
+          condition,
+          body,
+        }
+      }
+      _ => unreachable!("only called at a reserved word"),
+    };
+    let mut redirects = vec![];
+    while let Some(Token::Redirect(..)) = self.peek() {
+      redirects.push(self.redirect()?);
+    }
+    Ok(Node::Compound(compound, redirects))
+  }
+
+  fn redirect(&mut self) -> Result<Redirect, SyntaxError> {
+    let Some(Token::Redirect(fd, op)) = self.next() else {
+      unreachable!("only called at a redirection")
+    };
+    let Some(Token::Word(target)) = self.peek().cloned() else {
+      return Err(self.unexpected());
+    };
+    self.pos += 1;
+    let fd = fd.unwrap_or(if op.starts_with('<') { 0 } else { 1 });
+    Ok(Redirect {
+      fd,
+      op,
+      target,
+    })
+  }
+
+  /// The name of the `name=(` array assignment starting at the current word, which must be
+  /// followed by the `(` with nothing in between
+  fn array_assignment(&self) -> Option<String> {
+    let Some(Token::Word(word)) = self.peek() else {
+      return None;
+    };
+    let name = word.literal()?.strip_suffix('=').filter(|name| is_name(name))?.to_owned();
+    match (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+      (Some((_, word)), Some((Token::Operator("("), paren))) if word.end == paren.start => {
+        Some(name)
+      }
+      _ => None,
+    }
+  }
+
+  /// Parses the elements of an array assignment up to and including the `)`
+  fn array_elements(&mut self) -> Result<Vec<Word>, SyntaxError> {
+    let mut elements = vec![];
+    loop {
+      self.skip_newlines();
+      match self.peek() {
+        Some(Token::Word(word)) => {
+          elements.push(word.clone());
+          self.pos += 1;
+        }
+        Some(Token::Operator(")")) => {
+          self.pos += 1;
+          return Ok(elements);
+        }
+        _ => return Err(self.unexpected()),
+      }
+    }
+  }
+
+  fn simple_command(&mut self) -> Result<Node, SyntaxError> {
+    let start = self.tokens.get(self.pos).map_or(0, |(_, span)| span.start);
+    let mut command = SimpleCommand {
+      line: self.line_starts.partition_point(|line_start| *line_start <= start) + 1,
+      ..Default::default()
+    };
+    loop {
+      match self.peek() {
+        Some(Token::Word(word)) => {
+          if command.words.iter().all(|word| assignment(word).is_some())
+            && let Some(name) = self.array_assignment()
+          {
+            self.pos += 2;
+            let elements = self.array_elements()?;
+            command.arrays.push((name, elements));
+            continue;
+          }
+          command.words.push(word.clone());
+          self.pos += 1;
+        }
+        Some(Token::Redirect(..)) => command.redirects.push(self.redirect()?),
+        _ => break,
+      }
+    }
+    if command.words.is_empty() && command.arrays.is_empty() && command.redirects.is_empty() {
+      return Err(self.unexpected());
+    }
+    Ok(Node::Simple(command))
+  }
+}
+
+/// Parses the tokens `split_spanned` made of `input`
+pub fn parse(input: &str, tokens: Vec<(Token, Range<usize>)>) -> Result<List, SyntaxError> {
+  let mut parser = Parser {
+    input,
+    tokens,
+    pos: 0,
+    line_starts: input.match_indices('\n').map(|(i, _)| i + 1).collect(),
+  };
+  let list = parser.list()?;
+  match parser.peek() {
+    None => Ok(list),
+    Some(_) => Err(parser.unexpected()),
+  }
+}
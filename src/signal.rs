@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Pseudo-signal number `trap` uses for the shell exiting
+pub const EXIT: i32 = 0;
+
+pub const SIGNALS: [(i32, &str); 22] = [
+  (libc::SIGHUP, "HUP"),
+  (libc::SIGINT, "INT"),
+  (libc::SIGQUIT, "QUIT"),
+  (libc::SIGILL, "ILL"),
+  (libc::SIGTRAP, "TRAP"),
+  (libc::SIGABRT, "ABRT"),
+  (libc::SIGBUS, "BUS"),
+  (libc::SIGFPE, "FPE"),
+  (libc::SIGKILL, "KILL"),
+  (libc::SIGUSR1, "USR1"),
+  (libc::SIGSEGV, "SEGV"),
+  (libc::SIGUSR2, "USR2"),
+  (libc::SIGPIPE, "PIPE"),
+  (libc::SIGALRM, "ALRM"),
+  (libc::SIGTERM, "TERM"),
+  (libc::SIGCHLD, "CHLD"),
+  (libc::SIGCONT, "CONT"),
+  (libc::SIGSTOP, "STOP"),
+  (libc::SIGTSTP, "TSTP"),
+  (libc::SIGTTIN, "TTIN"),
+  (libc::SIGTTOU, "TTOU"),
+  (libc::SIGWINCH, "WINCH"),
+];
+
+/// Set by the signal handler and drained by the main loop, which runs the trap outside of the
+/// handler where it's safe to do anything
+static PENDING: [AtomicBool; 32] = [const { AtomicBool::new(false) }; 32];
+
+/// Set when a child has finished, trapped or not, for the shell to collect its status
+static CHILDREN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+  if signal == libc::SIGCHLD {
+    CHILDREN.store(true, Ordering::SeqCst);
+  }
+  PENDING[signal as usize].store(true, Ordering::SeqCst);
+}
+
+/// Parses `INT`, `SIGINT`, `2` or `EXIT` into a signal number
+pub fn parse(name: &str) -> Option<i32> {
+  if let Ok(number) = name.parse() {
+    return (number == EXIT || SIGNALS.iter().any(|(n, _)| *n == number)).then_some(number);
+  }
+  let name = name.to_ascii_uppercase();
+  let name = name.strip_prefix("SIG").unwrap_or(&name);
+  if name == "EXIT" {
+    return Some(EXIT);
+  }
+  SIGNALS.iter().find(|(_, n)| *n == name).map(|(number, _)| *number)
+}
+
+pub fn name(signal: i32) -> String {
+  match SIGNALS.iter().find(|(n, _)| *n == signal) {
+    Some((_, name)) => format!("SIG{name}"),
+    None => "EXIT".to_owned(),
+  }
+}
+
+pub enum Disposition {
+  Default,
+  Ignore,
+  Trap,
+}
+
+pub fn set_disposition(signal: i32, disposition: Disposition) {
+  if signal == EXIT {
+    return;
+  }
+  let handler = match disposition {
+    // The shell always hears of children finishing
+    Disposition::Default if signal == libc::SIGCHLD => on_signal as *const () as libc::sighandler_t,
+    Disposition::Default => libc::SIG_DFL,
+    Disposition::Ignore => libc::SIG_IGN,
+    Disposition::Trap => on_signal as *const () as libc::sighandler_t,
+  };
+  unsafe {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler;
+    // No SA_RESTART, so a blocking read at the prompt is interrupted to run the trap. Children
+    // finish too often for that, and would lose what's being typed.
+    action.sa_flags = match signal {
+      libc::SIGCHLD => libc::SA_RESTART | libc::SA_NOCLDSTOP,
+      _ => 0,
+    };
+    libc::sigemptyset(&mut action.sa_mask);
+    libc::sigaction(signal, &action, std::ptr::null_mut());
+  }
+}
+
+/// Whether any children have finished since the last call
+pub fn take_children() -> bool {
+  CHILDREN.swap(false, Ordering::SeqCst)
+}
+
+/// Returns the signals caught since the last call
+pub fn take_pending() -> Vec<i32> {
+  let pending = PENDING.iter().enumerate().filter(|(_, flag)| flag.swap(false, Ordering::SeqCst));
+  pending.map(|(signal, _)| signal as i32).collect()
+}
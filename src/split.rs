@@ -1,7 +1,120 @@
-use std::mem;
+use std::{fmt::Display, mem, ops::Range};
 
+use crate::{
+  builtin::{interpret_escapes, raw_text},
+  options::ShellOptions,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum ParseErrorKind {
+  /// `'` without its closing `'`
+  UnterminatedSingle,
+  /// `"` without its closing `"`
+  UnterminatedDouble,
+  /// `${` without its `}`
+  UnterminatedBrace,
+  /// `$((` without its `))`
+  UnterminatedArith,
+  /// `<(` or `>(` without its `)`
+  UnterminatedProcess,
+  /// A `\` at the end of the input, or just before a final newline, with nothing to escape
+  TrailingBackslash,
+}
+
+/// Why lexing failed, and the byte offset of the construct left open
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct ParseError;
+pub struct ParseError {
+  pub pos: usize,
+  pub kind: ParseErrorKind,
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self.kind {
+      ParseErrorKind::UnterminatedSingle => "unexpected EOF while looking for matching `''",
+      ParseErrorKind::UnterminatedDouble => "unexpected EOF while looking for matching `\"'",
+      ParseErrorKind::UnterminatedBrace => "unterminated parameter expansion",
+      ParseErrorKind::UnterminatedArith => "unterminated arithmetic expansion",
+      ParseErrorKind::UnterminatedProcess => "unterminated process substitution",
+      ParseErrorKind::TrailingBackslash => "unexpected EOF after `\\'",
+    })
+  }
+}
+
+/// A piece of a word along with whether it was quoted, so expansion knows what to leave alone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+  Literal(String, bool),
+  /// `$name` or `${expr}`, holding `name` or `expr`
+  Param(String, bool),
+  /// `$((expr))`, holding `expr`
+  Arith(String, bool),
+  /// `<(command)` or `>(command)`, holding `command` and whether the command is written to
+  Process(String, bool),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Word(pub Vec<Segment>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+  Word(Word),
+  /// `|`, `||`, `&`, `&&`, `;`, `(`, `)` or a newline
+  Operator(&'static str),
+  /// A redirection operator along with the file descriptor written before it, as in `2>>`
+  Redirect(Option<i32>, &'static str),
+}
+
+impl Word {
+  fn push(&mut self, c: char, quoted: bool) {
+    match self.0.last_mut() {
+      Some(Segment::Literal(text, q)) if *q == quoted => text.push(c),
+      _ => self.0.push(Segment::Literal(c.to_string(), quoted)),
+    }
+  }
+
+  /// Records that the word contains quotes, so that `""` still counts as a word
+  fn quote(&mut self) {
+    if !matches!(self.0.last(), Some(Segment::Literal(_, true))) {
+      self.0.push(Segment::Literal(String::new(), true));
+    }
+  }
+
+  /// The word's text if it's entirely unquoted literal text, as reserved words must be
+  pub fn literal(&self) -> Option<&str> {
+    match self.0.as_slice() {
+      [Segment::Literal(text, false)] => Some(text),
+      _ => None,
+    }
+  }
+}
+
+/// Iterates over the characters of the input, keeping track of the byte offset
+struct Cursor<'a> {
+  s: &'a str,
+  pos: usize,
+}
+
+impl Cursor<'_> {
+  fn peek(&self) -> Option<char> {
+    self.s[self.pos..].chars().next()
+  }
+
+  fn next_if(&mut self, f: impl FnOnce(char) -> bool) -> Option<char> {
+    let c = self.peek().filter(|c| f(*c))?;
+    self.pos += c.len_utf8();
+    Some(c)
+  }
+}
+
+impl Iterator for Cursor<'_> {
+  type Item = char;
+
+  fn next(&mut self) -> Option<char> {
+    self.next_if(|_| true)
+  }
+}
 
 enum State {
   Delimiter,
@@ -12,88 +125,377 @@ enum State {
   DoubleQuotedBackslash,
 }
 
-pub fn split(s: &str) -> Result<Vec<String>, ParseError> {
+/// Reads the arithmetic expression of a `$((`, given the cursor after it, up to the matching `))`
+fn arith(chars: &mut Cursor, start: usize) -> Result<String, ParseError> {
+  let mut expr = String::new();
+  let mut depth = 0;
+  loop {
+    let c = chars.next().ok_or(ParseError {
+      pos: start,
+      kind: ParseErrorKind::UnterminatedArith,
+    })?;
+    match c {
+      ')' if depth == 0 && chars.next_if(|c| c == ')').is_some() => return Ok(expr),
+      ')' => depth -= 1,
+      '(' => depth += 1,
+      _ => {}
+    }
+    expr.push(c);
+  }
+}
+
+/// Reads the command of a `<(` or `>(` at `start`, given the cursor after it, up to the matching `)`
+fn process(chars: &mut Cursor, start: usize) -> Result<String, ParseError> {
+  let error = ParseError {
+    pos: start,
+    kind: ParseErrorKind::UnterminatedProcess,
+  };
+  let mut command = String::new();
+  let mut depth = 0;
+  let mut quote = None;
+  loop {
+    let c = chars.next().ok_or(error)?;
+    match (quote, c) {
+      (None, ')') if depth == 0 => return Ok(command),
+      (None, ')') => depth -= 1,
+      (None, '(') => depth += 1,
+      (None, '\'' | '"') => quote = Some(c),
+      (Some(q), c) if q == c => quote = None,
+      (Some('\''), _) => {}
+      (_, '\\') => {
+        command.push(c);
+        command.push(chars.next().ok_or(error)?);
+        continue;
+      }
+      _ => {}
+    }
+    command.push(c);
+  }
+}
+
+/// Reads the expansion following the `$` at `start`, or `None` if the `$` is literal
+fn expansion(
+  chars: &mut Cursor,
+  start: usize,
+  quoted: bool,
+  posix: bool,
+) -> Result<Option<Segment>, ParseError> {
+  // `$'...'` is quoted text with backslash escapes, as `printf` has them, though not in POSIX
+  if !quoted && !posix && chars.next_if(|c| c == '\'').is_some() {
+    let mut text = String::new();
+    loop {
+      match chars.next() {
+        None => {
+          return Err(ParseError {
+            pos: start,
+            kind: ParseErrorKind::UnterminatedSingle,
+          });
+        }
+        Some('\'') => break,
+        Some('\\') => {
+          text.push('\\');
+          text.extend(chars.next());
+        }
+        Some(c) => text.push(c),
+      }
+    }
+    let mut out = vec![];
+    interpret_escapes(text.as_bytes(), false, &mut out);
+    return Ok(Some(Segment::Literal(raw_text(&out), true)));
+  }
+  Ok(parameter(chars, start)?.map(|(expr, arith)| match arith {
+    true => Segment::Arith(expr, quoted),
+    false => Segment::Param(expr, quoted),
+  }))
+}
+
+/// Reads the parameter following the `$` at `start`, along with whether it's arithmetic
+fn parameter(chars: &mut Cursor, start: usize) -> Result<Option<(String, bool)>, ParseError> {
+  let error = ParseError {
+    pos: start,
+    kind: ParseErrorKind::UnterminatedBrace,
+  };
+  if chars.s[chars.pos..].starts_with("((") {
+    chars.pos += 2;
+    return Ok(Some((arith(chars, start)?, true)));
+  }
+  match chars.peek() {
+    Some('{') => {
+      chars.next();
+      let mut expr = String::new();
+      let mut depth = 0;
+      let mut quote = None;
+      loop {
+        let c = chars.next().ok_or(error)?;
+        match (quote, c) {
+          (None, '}') if depth == 0 => break,
+          (None, '}') => depth -= 1,
+          (None, '{') => depth += 1,
+          (None, '\'' | '"') => quote = Some(c),
+          (Some(q), c) if q == c => quote = None,
+          (Some('\''), _) => {}
+          (_, '\\') => {
+            expr.push(c);
+            expr.push(chars.next().ok_or(error)?);
+            continue;
+          }
+          _ => {}
+        }
+        expr.push(c);
+      }
+      Ok(Some((expr, false)))
+    }
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+      let mut name = String::new();
+      while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || c == '_') {
+        name.push(c);
+      }
+      Ok(Some((name, false)))
+    }
+    Some(c) if "?#@*$!".contains(c) || c.is_ascii_digit() => {
+      chars.next();
+      Ok(Some((c.to_string(), false)))
+    }
+    _ => Ok(None),
+  }
+}
+
+/// Reads the operator starting with `c`
+fn operator(c: char, chars: &mut Cursor, fd: Option<i32>) -> Token {
+  let mut next = |second: char| chars.next_if(|c| c == second).is_some();
+  match c {
+    '|' if next('|') => Token::Operator("||"),
+    '|' => Token::Operator("|"),
+    '&' if next('&') => Token::Operator("&&"),
+    '&' => Token::Operator("&"),
+    ';' => Token::Operator(";"),
+    '(' => Token::Operator("("),
+    ')' => Token::Operator(")"),
+    '<' if next('&') => Token::Redirect(fd, "<&"),
+    '<' => Token::Redirect(fd, "<"),
+    '>' if next('>') => Token::Redirect(fd, ">>"),
+    '>' if next('&') => Token::Redirect(fd, ">&"),
+    '>' if next('|') => Token::Redirect(fd, ">"),
+    '>' => Token::Redirect(fd, ">"),
+    _ => Token::Operator("\n"),
+  }
+}
+
+/// Splits `s` into words and operators. With `extglob`, the `(`, `|` and `)` of pattern groups
+/// like `@(a|b)` are part of the word. With `posix`, the `$` of `$'...'` is literal and `<(` and
+/// `>(` are a redirection followed by `(`, not process substitution.
+pub fn split(s: &str, options: &ShellOptions) -> Result<Vec<Token>, ParseError> {
+  Ok(split_spanned(s, options)?.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Splits `s` into words and operators along with the byte range each came from
+pub fn split_spanned(
+  s: &str,
+  options: &ShellOptions,
+) -> Result<Vec<(Token, Range<usize>)>, ParseError> {
+  lex(s, false, options)
+}
+
+/// Lexes `s` as a single word, keeping whitespace, e.g. the `word` of `${VAR:-word}`
+pub fn word(s: &str, options: &ShellOptions) -> Result<Word, ParseError> {
+  match lex(s, true, options)?.pop() {
+    Some((Token::Word(word), _)) => Ok(word),
+    _ => Ok(Word::default()),
+  }
+}
+
+fn lex(
+  s: &str,
+  single_word: bool,
+  options: &ShellOptions,
+) -> Result<Vec<(Token, Range<usize>)>, ParseError> {
   use State::*;
+  let (extglob, posix) = (options.extglob && !single_word, options.posix);
+  let is_operator = |c: char| !single_word && "|&;()<>\n".contains(c);
   let mut state = Delimiter;
-  let mut words = vec![];
-  let mut word = String::new();
-  let mut chars = s.chars();
+  let mut tokens = vec![];
+  let mut word = Word::default();
+  let mut chars = Cursor {
+    s,
+    pos: 0,
+  };
+  // Where the current token and the innermost open quote started
+  let mut start = 0;
+  let mut quote_start = 0;
+  // How many `extglob` groups are open in the current word
+  let mut groups = 0;
+  let unterminated = |pos, kind| ParseError {
+    pos,
+    kind,
+  };
 
   loop {
+    let at = chars.pos;
     let c = chars.next();
+    if let Delimiter = state {
+      start = at;
+      groups = 0;
+    }
     state = match state {
       Delimiter => match c {
         None => break,
-        Some('\'') => SingleQuoted,
-        Some('\"') => DoubleQuoted,
+        Some('\'') => {
+          word.quote();
+          quote_start = at;
+          SingleQuoted
+        }
+        Some('\"') => {
+          word.quote();
+          quote_start = at;
+          DoubleQuoted
+        }
         Some('\\') => Backslash,
-        Some(w) if w.is_whitespace() => Delimiter,
+        Some('$') => {
+          match expansion(&mut chars, at, false, posix)? {
+            Some(segment) => word.0.push(segment),
+            None => word.push('$', false),
+          }
+          Unquoted
+        }
+        Some('#') if !single_word => {
+          while chars.next_if(|c| c != '\n').is_some() {}
+          Delimiter
+        }
+        Some(c @ ('<' | '>')) if !single_word && !posix && chars.peek() == Some('(') => {
+          chars.next();
+          word.0.push(Segment::Process(process(&mut chars, at)?, c == '>'));
+          Unquoted
+        }
+        Some(c) if is_operator(c) => {
+          tokens.push((operator(c, &mut chars, None), start..chars.pos));
+          Delimiter
+        }
+        Some(w) if w.is_whitespace() && !single_word => Delimiter,
         Some(c) => {
-          word.push(c);
+          word.push(c, false);
           Unquoted
         }
       },
       Unquoted => match c {
         None => {
-          words.push(mem::take(&mut word));
+          tokens.push((Token::Word(mem::take(&mut word)), start..at));
           break;
         }
-        Some('\'') => SingleQuoted,
-        Some('\"') => DoubleQuoted,
+        Some('\'') => {
+          word.quote();
+          quote_start = at;
+          SingleQuoted
+        }
+        Some('\"') => {
+          word.quote();
+          quote_start = at;
+          DoubleQuoted
+        }
         Some('\\') => Backslash,
-        Some(w) if w.is_whitespace() => {
-          words.push(mem::take(&mut word));
+        Some('$') => {
+          match expansion(&mut chars, at, false, posix)? {
+            Some(segment) => word.0.push(segment),
+            None => word.push('$', false),
+          }
+          Unquoted
+        }
+        Some('(')
+          if groups > 0
+            || extglob
+              && matches!(word.0.last(), Some(Segment::Literal(text, false))
+                if text.ends_with(['?', '*', '+', '@', '!'])) =>
+        {
+          groups += 1;
+          word.push('(', false);
+          Unquoted
+        }
+        Some(c @ ('|' | ')')) if groups > 0 => {
+          groups -= (c == ')') as usize;
+          word.push(c, false);
+          Unquoted
+        }
+        Some(c) if is_operator(c) => {
+          // Digits right before a redirection are the file descriptor it applies to
+          let fd = word
+            .literal()
+            .filter(|w| ['<', '>'].contains(&c) && w.bytes().all(|b| b.is_ascii_digit()));
+          let fd = fd.and_then(|fd| fd.parse().ok());
+          if fd.is_none() {
+            tokens.push((Token::Word(mem::take(&mut word)), start..at));
+            start = at;
+          }
+          word = Word::default();
+          tokens.push((operator(c, &mut chars, fd), start..chars.pos));
+          Delimiter
+        }
+        Some(w) if w.is_whitespace() && !single_word => {
+          tokens.push((Token::Word(mem::take(&mut word)), start..at));
           Delimiter
         }
         Some(c) => {
-          word.push(c);
+          word.push(c, false);
           Unquoted
         }
       },
       SingleQuoted => match c {
-        None => return Err(ParseError),
+        None => return Err(unterminated(quote_start, ParseErrorKind::UnterminatedSingle)),
         Some('\'') => Unquoted,
         Some(c) => {
-          word.push(c);
+          word.push(c, true);
           SingleQuoted
         }
       },
       DoubleQuoted => match c {
-        None => return Err(ParseError),
+        None => return Err(unterminated(quote_start, ParseErrorKind::UnterminatedDouble)),
         Some('\"') => Unquoted,
         Some('\\') => DoubleQuotedBackslash,
+        Some('$') => {
+          match expansion(&mut chars, at, true, posix)? {
+            Some(segment) => word.0.push(segment),
+            None => word.push('$', true),
+          }
+          DoubleQuoted
+        }
         Some(c) => {
-          word.push(c);
+          word.push(c, true);
           DoubleQuoted
         }
       },
       Backslash => match c {
+        None if !single_word => {
+          return Err(unterminated(at - 1, ParseErrorKind::TrailingBackslash));
+        }
+        Some('\n') if !single_word && chars.peek().is_none() => {
+          return Err(unterminated(at - 1, ParseErrorKind::TrailingBackslash));
+        }
         None => {
-          word.push('\\');
-          words.push(mem::take(&mut word));
+          word.push('\\', true);
+          tokens.push((Token::Word(mem::take(&mut word)), start..at));
           break;
         }
-        Some('\n') => Delimiter,
+        // A line continuation joins the lines, within a word too
+        Some('\n') if word.0.is_empty() => Delimiter,
+        Some('\n') => Unquoted,
         Some(c) => {
-          word.push(c);
+          word.push(c, true);
           Unquoted
         }
       },
       DoubleQuotedBackslash => match c {
-        None => return Err(ParseError),
+        None => return Err(unterminated(quote_start, ParseErrorKind::UnterminatedDouble)),
         Some('\n') => DoubleQuoted,
         Some(c) if ['$', '`', '"', '\\'].contains(&c) => {
-          word.push(c);
+          word.push(c, true);
           DoubleQuoted
         }
         Some(c) => {
-          word.push('\\');
-          word.push(c);
+          word.push('\\', true);
+          word.push(c, true);
           DoubleQuoted
         }
       },
     }
   }
 
-  Ok(words)
+  Ok(tokens)
 }